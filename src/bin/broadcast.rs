@@ -0,0 +1,9 @@
+use festrom::broadcast::{BroadcastMode, BroadcastNode};
+use festrom::config::GossipConfig;
+use festrom::{runtime, Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = GossipConfig::load()?;
+    runtime::run(BroadcastNode::new(BroadcastMode::from_env(), config)).await
+}
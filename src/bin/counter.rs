@@ -0,0 +1,7 @@
+use festrom::counter::CounterNode;
+use festrom::{runtime, Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    runtime::run(CounterNode).await
+}
@@ -0,0 +1,30 @@
+use festrom::runtime::{self, Context, Handler};
+use festrom::{Error, Message};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    // Echo (probably mainly used for heartbeat)
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+struct Echo;
+
+impl Handler for Echo {
+    type Payload = Payload;
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        match &msg.body.message {
+            Payload::Echo { echo } => ctx.reply(&msg, Payload::EchoOk { echo: echo.clone() }),
+            _ => ctx.not_supported(&msg),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    runtime::run(Echo).await
+}
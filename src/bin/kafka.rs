@@ -0,0 +1,7 @@
+use festrom::kafka::KafkaNode;
+use festrom::{runtime, Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    runtime::run(KafkaNode::default()).await
+}
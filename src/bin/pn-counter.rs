@@ -0,0 +1,9 @@
+use festrom::config::GossipConfig;
+use festrom::pn_counter::PnCounterNode;
+use festrom::{runtime, Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = GossipConfig::load()?;
+    runtime::run(PnCounterNode::new(config)).await
+}
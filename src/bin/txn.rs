@@ -0,0 +1,9 @@
+use festrom::config::GossipConfig;
+use festrom::txn::{Isolation, TxnNode};
+use festrom::{runtime, Error};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let config = GossipConfig::load()?;
+    runtime::run(TxnNode::new(Isolation::from_env(), config)).await
+}
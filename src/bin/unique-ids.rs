@@ -0,0 +1,34 @@
+use festrom::runtime::{self, Context, Handler};
+use festrom::{Error, Message};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+enum Payload {
+    // Used for generating unique IDs
+    Generate,
+    GenerateOk { id: String },
+}
+
+struct UniqueIds;
+
+impl Handler for UniqueIds {
+    type Payload = Payload;
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        match &msg.body.message {
+            Payload::Generate => {
+                let id = format!("{}-{}", ctx.node_id(), Ulid::new());
+                ctx.reply(&msg, Payload::GenerateOk { id });
+            }
+            _ => ctx.not_supported(&msg),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    runtime::run(UniqueIds).await
+}
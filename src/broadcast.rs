@@ -0,0 +1,1186 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::str::FromStr;
+
+use rand::prelude::IteratorRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tracing::warn;
+
+use crate::config::{Exchange, GossipConfig, PeerSelection};
+use crate::encoding::Values;
+use crate::hash::mix;
+use crate::iblt::Iblt;
+use crate::merkle::{MerkleTree, Node};
+use crate::replication::ReplicatedLog;
+use crate::rpc::{Rpc, RpcError};
+use crate::runtime::{Context, Handler};
+use crate::timer::TimerWheel;
+use crate::topology::Topology;
+use crate::Message;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    // Used for topology management
+    Topology {
+        topology: HashMap<String, HashSet<String>>,
+    },
+    TopologyOk,
+
+    // Used for broadcasting messages
+    Broadcast {
+        message: usize,
+    },
+    BroadcastOk,
+
+    // Used for reading messages
+    Read,
+    ReadOk {
+        messages: HashSet<usize>,
+    },
+
+    // Used for Gossiping with other nodes
+    Gossip {
+        has_seen: BTreeSet<usize>,
+    },
+    GossipOk,
+    // Sequence-numbered gossip: `values` follow the first `from` values in
+    // the sender's log, and `ack` is how much of the receiver's log the
+    // sender has without gaps
+    Delta {
+        from: usize,
+        values: Values,
+        ack: usize,
+    },
+    // A delta that also asks for the receiver's delta in return
+    DeltaPull {
+        from: usize,
+        values: Values,
+        ack: usize,
+    },
+
+    // Used for eager pushes, as in Plumtree
+    Push {
+        message: usize,
+    },
+    // Stop pushing to me, I already get your values from someone else
+    Prune,
+    // Start pushing to me again, I only got your values through gossip
+    Graft,
+
+    // Used for set reconciliation: a digest of everything the sender has,
+    // which includes the first `log` values of its log
+    Digest {
+        log: usize,
+        table: Iblt,
+    },
+    // The values the receiver's digest showed it was missing. `ack` works as
+    // in a delta. When the digest couldn't be decoded, `undecoded` is set and
+    // `values` are instead some of what the receiver hasn't acknowledged, up
+    // to `max_payload` of them
+    Reconcile {
+        values: Values,
+        ack: usize,
+        undecoded: bool,
+    },
+
+    // Used for Merkle anti-entropy: the sender's hashes for the nodes of its
+    // tree the receiver should compare
+    MerkleHashes {
+        nodes: Vec<(Node, u64)>,
+    },
+    // The sender's values in leaves where the trees differ. With `reply`
+    // set, the receiver sends back whichever of its own values in those
+    // leaves the sender lacks
+    MerkleRepair {
+        leaves: Vec<Node>,
+        values: Values,
+        reply: bool,
+    },
+}
+
+/// First retry delay for an unacknowledged batch, also used as its RPC timeout.
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Peer schedules are checked this many times per gossip period, which bounds
+/// how long a new value waits before it's sent.
+const TICKS_PER_PERIOD: u32 = 5;
+/// Peers with nothing to exchange back off to at most this many periods.
+const MAX_IDLE_PERIODS: u32 = 8;
+const WHEEL_SLOTS: usize = 64;
+
+/// Digests start out with room for about 20 differences, and double each
+/// time one has too many to decode.
+const MIN_DIGEST_CELLS: usize = 30;
+const MAX_DIGEST_CELLS: usize = 30 << 10;
+
+/// 256 leaves, so about a dozen differences are found in 9 round trips.
+const MERKLE_DEPTH: u32 = 8;
+
+/// How broadcast values travel between nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BroadcastMode {
+    /// Fire-and-forget gossip on every tick.
+    #[default]
+    Gossip,
+    /// Forward new values once and retry until the peer acknowledges them.
+    Acked,
+    /// Push new values to neighbours as soon as they arrive, pruning links
+    /// that only bring duplicates, and gossip to repair whatever the pushes
+    /// missed.
+    Eager,
+}
+
+impl BroadcastMode {
+    /// Reads the mode from `FESTROM_BROADCAST_MODE`, falling back to gossip.
+    pub fn from_env() -> Self {
+        std::env::var("FESTROM_BROADCAST_MODE")
+            .ok()
+            .and_then(|mode| mode.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for BroadcastMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gossip" => Ok(BroadcastMode::Gossip),
+            "acked" => Ok(BroadcastMode::Acked),
+            "eager" => Ok(BroadcastMode::Eager),
+            other => Err(format!("Unknown broadcast mode: {}", other)),
+        }
+    }
+}
+
+/// Keeps one replication task per peer. Each task holds the values its peer
+/// hasn't acknowledged yet and keeps resending them, with exponential backoff,
+/// until a `gossip_ok` comes back.
+#[derive(Default)]
+pub struct AckedBroadcast {
+    peers: HashMap<String, mpsc::UnboundedSender<BTreeSet<usize>>>,
+    /// The most values sent in one batch, the rest wait for the next one.
+    max_batch: Option<usize>,
+}
+
+impl AckedBroadcast {
+    pub fn new(max_batch: Option<usize>) -> Self {
+        AckedBroadcast {
+            max_batch,
+            ..Default::default()
+        }
+    }
+
+    /// Queues `values` for delivery to `peer`.
+    pub fn forward(&mut self, rpc: &Rpc, peer: &str, values: BTreeSet<usize>) {
+        if values.is_empty() {
+            return;
+        }
+        let tx = self.peers.entry(peer.to_string()).or_insert_with(|| {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let max_batch = self.max_batch.unwrap_or(usize::MAX);
+            tokio::spawn(replicate(rpc.clone(), peer.to_string(), rx, max_batch));
+            tx
+        });
+        // The task only stops once we drop the sender
+        let _ = tx.send(values);
+    }
+}
+
+/// The broadcast workload: every value broadcast to any node must eventually
+/// be read from all of them.
+pub struct BroadcastNode {
+    topology: Topology,
+    messages: HashSet<usize>,
+    /// Values in the order we learned them.
+    log: ReplicatedLog<usize>,
+    /// When each peer is next due for gossip.
+    schedule: TimerWheel<String>,
+    /// Time between rounds with each peer, which grows while there is
+    /// nothing to exchange with it.
+    intervals: HashMap<String, Duration>,
+    /// Peers that asked us not to push to them, for eager mode.
+    lazy: HashSet<String>,
+    /// How big a digest each peer can decode, for digest exchanges.
+    digest_cells: HashMap<String, usize>,
+    /// Digests of everything we have, by size, kept up to date as we learn.
+    digests: HashMap<usize, Iblt>,
+    /// A Merkle tree of everything we have, for Merkle exchanges.
+    merkle: MerkleTree,
+    /// Our values by the index of the Merkle leaf they fall in.
+    buckets: HashMap<u64, Vec<usize>>,
+    /// Peers whose last Merkle or digest exchange with us found nothing to
+    /// repair, until we learn something new.
+    in_sync: HashSet<String>,
+    /// Peers we pulled from that haven't sent any values back since.
+    pulled: HashSet<String>,
+    mode: BroadcastMode,
+    config: GossipConfig,
+    acked: AckedBroadcast,
+}
+
+impl BroadcastNode {
+    pub fn new(mode: BroadcastMode, config: GossipConfig) -> Self {
+        BroadcastNode {
+            topology: Topology::new(),
+            messages: HashSet::new(),
+            log: ReplicatedLog::default(),
+            schedule: TimerWheel::new(config.period / TICKS_PER_PERIOD, WHEEL_SLOTS),
+            intervals: HashMap::new(),
+            lazy: HashSet::new(),
+            digest_cells: HashMap::new(),
+            digests: HashMap::new(),
+            merkle: MerkleTree::new(MERKLE_DEPTH),
+            buckets: HashMap::new(),
+            in_sync: HashSet::new(),
+            pulled: HashSet::new(),
+            mode,
+            acked: AckedBroadcast::new(config.max_payload),
+            config,
+        }
+    }
+
+    /// Adds `value` to what we know, numbering it if it's new.
+    fn learn(&mut self, value: usize) -> bool {
+        let new = self.messages.insert(value);
+        if new {
+            self.log.push(value);
+            for digest in self.digests.values_mut() {
+                digest.insert(value);
+            }
+            let key = mix(value as u64);
+            self.merkle.insert(key, value as u64);
+            let leaf = self.merkle.leaf(key);
+            self.buckets.entry(leaf.index).or_default().push(value);
+            self.in_sync.clear();
+        }
+        // Eager pushes already send new values on, so gossip can stay lazy
+        if new && self.mode != BroadcastMode::Eager {
+            // Nobody has this yet, so every peer is due on the next tick
+            for (peer, interval) in self.intervals.iter_mut() {
+                *interval = self.config.period;
+                self.schedule.schedule(peer.clone(), Duration::ZERO);
+            }
+        }
+        new
+    }
+
+    /// The `Delta` for `peer`, from `ReplicatedLog::delta`.
+    fn delta(&self, peer: &str) -> (usize, Values, usize) {
+        let (from, values, ack) =
+            self.log
+                .delta(peer, self.config.max_payload, self.config.redundancy);
+        (
+            from,
+            Values::encode(values.to_vec(), self.config.encoding),
+            ack,
+        )
+    }
+
+    /// Merges values from `peer`'s log. Returns whether any of them were new
+    /// to us.
+    fn receive(&mut self, peer: &str, from: usize, values: &Values) -> bool {
+        let mut new = false;
+        for value in values.iter() {
+            new |= self.learn(value);
+        }
+        self.log.receive(peer, from, values.len());
+        new
+    }
+
+    /// Our digest with `cells` cells.
+    fn digest(&mut self, cells: usize) -> &Iblt {
+        let log = self.log.entries();
+        self.digests
+            .entry(cells)
+            .or_insert_with(|| Iblt::from_values(cells, log.iter().copied()))
+    }
+
+    /// Our values that fall under any of `nodes` of the Merkle tree.
+    fn merkle_values<'a>(&'a self, nodes: &'a [Node]) -> impl Iterator<Item = usize> + 'a {
+        nodes
+            .iter()
+            .flat_map(|node| self.merkle.leaves(*node))
+            .filter_map(|leaf| self.buckets.get(&leaf))
+            .flatten()
+            .copied()
+    }
+
+    /// Pushes a value we just learned to every neighbour that hasn't pruned
+    /// us, except the one we got it from.
+    fn push(&self, ctx: &Context, message: usize, from: &str) {
+        for peer in self.gossip_peers(ctx) {
+            if peer != from && !self.lazy.contains(&peer) {
+                ctx.send(&peer, Payload::Push { message });
+            }
+        }
+    }
+
+    /// In eager mode, values that only reached us through gossip mean the
+    /// push tree is missing a link to `peer`.
+    fn graft(&mut self, ctx: &Context, peer: &str, new: bool) {
+        if new && self.mode == BroadcastMode::Eager {
+            ctx.send(peer, Payload::Graft);
+        }
+    }
+
+    /// Hands newly learned values to every gossip peer except the one we got
+    /// them from, to be retried until each of them acknowledges.
+    fn forward(&mut self, ctx: &Context, values: &BTreeSet<usize>, from: &str) {
+        for peer in self.gossip_peers(ctx) {
+            if peer != from {
+                self.acked.forward(ctx.rpc(), &peer, values.clone());
+            }
+        }
+    }
+
+    /// Who we gossip, push or forward to: our neighbours, or the whole
+    /// cluster, in a fixed order so that a seeded run sends the same way.
+    fn gossip_peers(&self, ctx: &Context) -> Vec<String> {
+        let mut peers: Vec<String> = match self.config.peers {
+            PeerSelection::Topology => self
+                .topology
+                .get(ctx.node_id())
+                .map(|peers| peers.iter().cloned().collect())
+                .unwrap_or_default(),
+            PeerSelection::Random => ctx.peers().cloned().collect(),
+        };
+        peers.sort();
+        peers
+    }
+
+    /// Sets `peer`'s next round `interval` from now, plus jitter.
+    fn reschedule(&mut self, ctx: &Context, peer: String, interval: Duration) {
+        let jitter = self.config.jitter.mul_f64(ctx.rng().gen());
+        self.intervals.insert(peer.clone(), interval);
+        self.schedule.schedule(peer, interval + jitter);
+    }
+
+    fn gossip(&mut self, ctx: &Context) {
+        if self.mode == BroadcastMode::Acked {
+            // Acked forwarding retries on its own, so there is nothing to repair
+            return;
+        }
+        let peers = self.gossip_peers(ctx);
+        // New peers, e.g. after a topology change, are due right away
+        for peer in &peers {
+            if !self.schedule.is_scheduled(peer) {
+                self.intervals.insert(peer.clone(), self.config.period);
+                self.schedule.schedule(peer.clone(), Duration::ZERO);
+            }
+        }
+        // In a fixed order, so that a seeded rng picks the same peers
+        let mut due: Vec<String> = self
+            .schedule
+            .advance()
+            .into_iter()
+            .filter(|peer| peers.contains(peer))
+            .collect();
+        due.sort();
+        let due = match self.config.fan_out {
+            Some(fan_out) if due.len() > fan_out => {
+                let chosen = due
+                    .iter()
+                    .cloned()
+                    .choose_multiple(&mut *ctx.rng(), fan_out);
+                // Whoever wasn't picked waits for another round
+                for peer in due.into_iter().filter(|peer| !chosen.contains(peer)) {
+                    let interval = self.intervals[&peer];
+                    self.reschedule(ctx, peer, interval);
+                }
+                chosen
+            }
+            _ => due,
+        };
+
+        for node in due {
+            let (idle, payload) = match self.config.exchange {
+                Exchange::Push => {
+                    let (from, values, ack) = self.delta(&node);
+                    let idle = !self.log.has_news_for(&node);
+                    (idle, Payload::Delta { from, values, ack })
+                }
+                // Whether the peer has news for us only shows in its replies
+                Exchange::PushPull => {
+                    let (from, values, ack) = self.delta(&node);
+                    let idle = !self.log.has_news_for(&node) && self.pulled.contains(&node);
+                    self.pulled.insert(node.clone());
+                    (idle, Payload::DeltaPull { from, values, ack })
+                }
+                Exchange::Pull => {
+                    let idle = self.pulled.contains(&node);
+                    self.pulled.insert(node.clone());
+                    let payload = Payload::DeltaPull {
+                        from: self.log.acknowledged(&node),
+                        values: Values::default(),
+                        ack: self.log.received(&node),
+                    };
+                    (idle, payload)
+                }
+                Exchange::Merkle => (
+                    self.in_sync.contains(&node),
+                    Payload::MerkleHashes {
+                        nodes: vec![self.merkle.root()],
+                    },
+                ),
+                Exchange::Digest => {
+                    let cells = self
+                        .digest_cells
+                        .get(&node)
+                        .copied()
+                        .unwrap_or(MIN_DIGEST_CELLS);
+                    let payload = Payload::Digest {
+                        log: self.log.len(),
+                        table: self.digest(cells).clone(),
+                    };
+                    (self.in_sync.contains(&node), payload)
+                }
+            };
+            let interval = if idle {
+                (self.intervals[&node] * 2).min(self.config.period * MAX_IDLE_PERIODS)
+            } else {
+                self.config.period
+            };
+            self.reschedule(ctx, node.clone(), interval);
+            if idle && self.config.exchange == Exchange::Push {
+                // Nothing new for this peer, but others may still need theirs
+                continue;
+            }
+            match &payload {
+                Payload::Delta { from, values, .. } | Payload::DeltaPull { from, values, .. } => {
+                    self.log.sent(&node, from + values.len())
+                }
+                _ => self.log.ack_sent(&node),
+            };
+            ctx.send(&node, payload);
+        }
+    }
+}
+
+impl Handler for BroadcastNode {
+    type Payload = Payload;
+
+    /// Topologies we compute ourselves don't need to wait for Maelstrom's.
+    fn init(&mut self, ctx: &Context) {
+        self.topology = self
+            .config
+            .topology
+            .build(ctx.node_ids(), &Default::default());
+    }
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        use Payload::*;
+
+        match &msg.body.message {
+            Topology { topology } => {
+                self.topology = self.config.topology.build(ctx.node_ids(), topology);
+                ctx.reply(&msg, TopologyOk);
+            }
+            Broadcast { message } => {
+                if self.learn(*message) {
+                    match self.mode {
+                        BroadcastMode::Acked => {
+                            self.forward(ctx, &BTreeSet::from([*message]), &msg.src)
+                        }
+                        BroadcastMode::Eager => self.push(ctx, *message, &msg.src),
+                        BroadcastMode::Gossip => {}
+                    }
+                }
+                ctx.reply(&msg, BroadcastOk);
+            }
+            Read => {
+                ctx.reply(
+                    &msg,
+                    ReadOk {
+                        messages: self.messages.clone(),
+                    },
+                );
+            }
+            Gossip { has_seen } => {
+                let new: BTreeSet<usize> = has_seen
+                    .iter()
+                    .copied()
+                    .filter(|value| self.learn(*value))
+                    .collect();
+                if self.mode == BroadcastMode::Acked {
+                    self.forward(ctx, &new, &msg.src);
+                }
+                if msg.body.msg_id.is_some() {
+                    ctx.reply(&msg, GossipOk);
+                }
+            }
+            Delta { from, values, ack } => {
+                self.log.acknowledge(&msg.src, *ack);
+                let new = self.receive(&msg.src, *from, values);
+                self.graft(ctx, &msg.src, new);
+                // A pull that brings values back is worth repeating soon,
+                // unless learning them already saw to that
+                let period = self.config.period;
+                let backed_off = self.intervals.get(&msg.src) > Some(&period);
+                if !values.is_empty() && self.pulled.remove(&msg.src) && backed_off {
+                    self.reschedule(ctx, msg.src.clone(), period);
+                }
+            }
+            DeltaPull { from, values, ack } => {
+                self.log.acknowledge(&msg.src, *ack);
+                let new = self.receive(&msg.src, *from, values);
+                self.graft(ctx, &msg.src, new);
+                let (from, values, ack) = self.delta(&msg.src);
+                let owed = self.log.sent(&msg.src, from + values.len());
+                if !values.is_empty() || owed {
+                    ctx.send(&msg.src, Delta { from, values, ack });
+                }
+            }
+            Push { message } => {
+                if self.learn(*message) {
+                    self.push(ctx, *message, &msg.src);
+                } else {
+                    ctx.send(&msg.src, Prune);
+                }
+            }
+            Prune => {
+                self.lazy.insert(msg.src.clone());
+            }
+            Graft => {
+                self.lazy.remove(&msg.src);
+            }
+            Digest { log, table } => {
+                let cells = table.len();
+                let valid = cells % MIN_DIGEST_CELLS == 0
+                    && (cells / MIN_DIGEST_CELLS).is_power_of_two()
+                    && cells <= MAX_DIGEST_CELLS;
+                if !valid {
+                    // We only keep digests of the sizes we'd ask for ourselves
+                    warn!(src = %msg.src, cells, "Ignoring a digest of an unexpected size");
+                    return;
+                }
+                let ours = self.digest(cells);
+                let reply = match table.subtract(ours).and_then(Iblt::decode) {
+                    Some((missing, extra)) => {
+                        if missing.is_empty() && extra.is_empty() {
+                            self.in_sync.insert(msg.src.clone());
+                        } else {
+                            self.in_sync.remove(&msg.src);
+                        }
+                        let mut new = false;
+                        for value in missing {
+                            new |= self.learn(value);
+                        }
+                        self.graft(ctx, &msg.src, new);
+                        Reconcile {
+                            values: Values::encode(extra, self.config.encoding),
+                            ack: *log,
+                            undecoded: false,
+                        }
+                    }
+                    None => {
+                        self.in_sync.remove(&msg.src);
+                        let from = self.log.acknowledged(&msg.src);
+                        let unacked = self.log.entries()[from..].iter().copied();
+                        let values = match self.config.max_payload {
+                            Some(max_payload) => {
+                                unacked.choose_multiple(&mut *ctx.rng(), max_payload)
+                            }
+                            None => unacked.collect(),
+                        };
+                        Reconcile {
+                            values: Values::encode(values, self.config.encoding),
+                            ack: 0,
+                            undecoded: true,
+                        }
+                    }
+                };
+                ctx.send(&msg.src, reply);
+            }
+            MerkleHashes { nodes } if nodes.is_empty() => {
+                // The peer found that our trees match
+                self.in_sync.insert(msg.src.clone());
+            }
+            MerkleHashes { nodes } => {
+                let comparison = self.merkle.compare(nodes);
+                if comparison.next.is_empty() && comparison.leaves.is_empty() {
+                    self.in_sync.insert(msg.src.clone());
+                    ctx.send(&msg.src, MerkleHashes { nodes: Vec::new() });
+                    return;
+                }
+                self.in_sync.remove(&msg.src);
+                if !comparison.next.is_empty() {
+                    let nodes = comparison.next;
+                    ctx.send(&msg.src, MerkleHashes { nodes });
+                }
+                if !comparison.leaves.is_empty() {
+                    let values = self.merkle_values(&comparison.leaves).collect();
+                    let repair = MerkleRepair {
+                        leaves: comparison.leaves,
+                        values: Values::encode(values, self.config.encoding),
+                        reply: true,
+                    };
+                    ctx.send(&msg.src, repair);
+                }
+            }
+            MerkleRepair {
+                leaves,
+                values,
+                reply,
+            } => {
+                if let Some(leaf) = leaves.iter().find(|leaf| !self.merkle.has(**leaf)) {
+                    warn!(src = %msg.src, ?leaf, "Ignoring a repair for a node we don't have");
+                    return;
+                }
+                let mut new = false;
+                for value in values.iter() {
+                    new |= self.learn(value);
+                }
+                self.graft(ctx, &msg.src, new);
+                if *reply {
+                    let theirs: HashSet<usize> = values.iter().collect();
+                    let extra: Vec<usize> = self
+                        .merkle_values(leaves)
+                        .filter(|value| !theirs.contains(value))
+                        .collect();
+                    if !extra.is_empty() {
+                        let repair = MerkleRepair {
+                            leaves: leaves.clone(),
+                            values: Values::encode(extra, self.config.encoding),
+                            reply: false,
+                        };
+                        ctx.send(&msg.src, repair);
+                    }
+                }
+            }
+            Reconcile {
+                values,
+                ack,
+                undecoded,
+            } => {
+                self.log.acknowledge(&msg.src, *ack);
+                if *undecoded || !values.is_empty() {
+                    self.in_sync.remove(&msg.src);
+                } else {
+                    self.in_sync.insert(msg.src.clone());
+                }
+                let mut new = false;
+                for value in values.iter() {
+                    new |= self.learn(value);
+                }
+                self.graft(ctx, &msg.src, new);
+                if *undecoded {
+                    // Our digest was too small, so send a bigger one next time
+                    let cells = self
+                        .digest_cells
+                        .entry(msg.src.clone())
+                        .or_insert(MIN_DIGEST_CELLS);
+                    *cells = (*cells * 2).min(MAX_DIGEST_CELLS);
+                }
+            }
+            _ => ctx.not_supported(&msg),
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &Context) {
+        self.gossip(ctx);
+    }
+
+    /// Each peer keeps its own schedule, so we tick often enough to catch
+    /// whoever is due. Jitter is added per peer rather than here.
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.schedule.resolution())
+    }
+}
+
+impl Default for BroadcastNode {
+    fn default() -> Self {
+        BroadcastNode::new(BroadcastMode::default(), GossipConfig::default())
+    }
+}
+
+async fn replicate(
+    rpc: Rpc,
+    peer: String,
+    mut rx: mpsc::UnboundedReceiver<BTreeSet<usize>>,
+    max_batch: usize,
+) {
+    // Sorted, so that batches are made up the same way on every run
+    let mut unacked = BTreeSet::new();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        if unacked.is_empty() {
+            match rx.recv().await {
+                Some(values) => unacked.extend(values),
+                None => return,
+            }
+        }
+        while let Ok(values) = rx.try_recv() {
+            unacked.extend(values);
+        }
+
+        let batch: BTreeSet<usize> = unacked.iter().copied().take(max_batch).collect();
+        let reply: Result<Payload, _> = rpc
+            .rpc_with_timeout(
+                peer.clone(),
+                Payload::Gossip {
+                    has_seen: batch.clone(),
+                },
+                backoff,
+            )
+            .await;
+        match reply {
+            Ok(_) => {
+                unacked.retain(|value| !batch.contains(value));
+                backoff = MIN_BACKOFF;
+                continue;
+            }
+            // The timeout already waited out the backoff
+            Err(RpcError::Timeout { .. }) => {}
+            Err(err) => {
+                warn!(%peer, %err, "Gossip failed");
+                time::sleep(backoff).await;
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    use crate::rpc::Outbound;
+    use crate::runtime;
+    use crate::sim::{Network, Simulation};
+    use crate::topology::TopologyStrategy;
+    use crate::MessageBody;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn message(src: &str, msg_id: usize, body: serde_json::Value) -> Message<serde_json::Value> {
+        Message {
+            src: src.to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                message: body,
+            },
+        }
+    }
+
+    #[test]
+    fn test_eager_push_beats_gossip() {
+        // Gossip is far too slow to deliver anything within the test
+        let config = GossipConfig {
+            period: Duration::from_secs(10),
+            topology: TopologyStrategy::Ring,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(5, Network::default(), 5, || {
+            BroadcastNode::new(BroadcastMode::Eager, config.clone())
+        });
+        for (message, node) in ["n0", "n2", "n4"].into_iter().enumerate() {
+            let reply: Option<Payload> = sim.call(node, Payload::Broadcast { message }, TIMEOUT);
+            assert_eq!(reply, Some(Payload::BroadcastOk));
+            sim.run_for(Duration::from_millis(100));
+
+            let expected: HashSet<usize> = (0..=message).collect();
+            let node_ids: Vec<String> = sim.node_ids().cloned().collect();
+            for node in node_ids {
+                assert_eq!(
+                    sim.node(&node).messages,
+                    expected,
+                    "{} missed an eager push",
+                    node
+                );
+            }
+        }
+        // Both pushes around the ring met somewhere, and one side backed off
+        let pruned: usize = (0..5)
+            .map(|i| sim.node(&format!("n{}", i)).lazy.len())
+            .sum();
+        assert!(pruned > 0, "No link was pruned");
+    }
+
+    #[test]
+    fn test_seeded_runs_push_the_same_way() {
+        let config = GossipConfig {
+            topology: TopologyStrategy::SmallWorld,
+            ..Default::default()
+        };
+        let run = || {
+            let mut sim = Simulation::new(4, Network::default(), 8, || {
+                BroadcastNode::new(BroadcastMode::Eager, config.clone())
+            });
+            for message in 0..20 {
+                sim.request(&format!("n{}", message % 8), Payload::Broadcast { message });
+            }
+            sim.run_for(Duration::from_secs(1));
+            // The order values arrived in shows the order they were pushed in
+            (0..8)
+                .map(|i| sim.node(&format!("n{}", i)).log.entries().to_vec())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(run(), run());
+    }
+
+    /// Ticks for a whole gossip period and returns what was sent.
+    fn gossip(
+        node: &mut BroadcastNode,
+        ctx: &Context,
+        out: &mut mpsc::UnboundedReceiver<Outbound>,
+    ) -> Vec<serde_json::Value> {
+        while out.try_recv().is_ok() {}
+        for _ in 0..TICKS_PER_PERIOD {
+            node.on_tick(ctx);
+        }
+        std::iter::from_fn(|| out.try_recv().ok())
+            .map(|outbound| outbound.body.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_random_peers_come_from_the_whole_cluster() {
+        let (rpc, mut out) = Rpc::new();
+        let mut ctx = Context::seeded(rpc, 1);
+        let config = GossipConfig {
+            peers: PeerSelection::Random,
+            exchange: Exchange::PushPull,
+            fan_out: Some(2),
+            ..Default::default()
+        };
+        let mut node = BroadcastNode::new(BroadcastMode::Gossip, config);
+        // No topology is ever sent, so peers can only come from the cluster
+        let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2", "n3", "n4", "n5", "n6"]});
+        for msg in [
+            message("c1", 1, init),
+            message("c1", 2, json!({"type": "broadcast", "message": 10})),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+
+        let mut reached = HashSet::new();
+        for _ in 0..5 {
+            while out.try_recv().is_ok() {}
+            for _ in 0..TICKS_PER_PERIOD {
+                node.on_tick(&ctx);
+            }
+            let round: Vec<Outbound> = std::iter::from_fn(|| out.try_recv().ok()).collect();
+            assert_eq!(round.len(), 2, "Fan-out was not respected");
+            for outbound in round {
+                assert_eq!(
+                    outbound.body.message,
+                    json!({"type": "delta_pull", "from": 0, "values": [10], "ack": 0})
+                );
+                reached.insert(outbound.dest);
+            }
+        }
+        assert!(reached.len() > 2, "Only ever gossiped to {:?}", reached);
+
+        // A pull is answered with whatever the puller hasn't acknowledged
+        let pull = json!({"type": "delta_pull", "from": 0, "values": [], "ack": 0});
+        runtime::dispatch(&mut node, &mut ctx, message("n4", 1, pull));
+        let reply = out.try_recv().expect("Pull was not answered");
+        assert_eq!(reply.dest, "n4");
+        assert_eq!(
+            reply.body.message,
+            json!({"type": "delta", "from": 0, "values": [10], "ack": 0})
+        );
+    }
+
+    #[test]
+    fn test_undecodable_digest_falls_back_to_unacked_values() {
+        let (rpc, mut out) = Rpc::new();
+        let mut ctx = Context::new(rpc);
+        let config = GossipConfig {
+            exchange: Exchange::Digest,
+            max_payload: Some(3),
+            ..Default::default()
+        };
+        let mut node = BroadcastNode::new(BroadcastMode::Gossip, config);
+        for msg in [
+            message(
+                "c1",
+                1,
+                json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2"]}),
+            ),
+            message(
+                "c1",
+                2,
+                json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+            ),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+        for message in 0..10 {
+            node.learn(message);
+        }
+        let acked = Payload::Reconcile {
+            values: Values::default(),
+            ack: 7,
+            undecoded: false,
+        };
+        let acked = serde_json::to_value(acked).unwrap();
+        runtime::dispatch(&mut node, &mut ctx, message("n2", 1, acked));
+
+        // Far more differences than the smallest digest can decode
+        let digest = Payload::Digest {
+            log: 100,
+            table: Iblt::from_values(MIN_DIGEST_CELLS, 100..200),
+        };
+        let digest = serde_json::to_value(digest).unwrap();
+        while out.try_recv().is_ok() {}
+        runtime::dispatch(&mut node, &mut ctx, message("n2", 2, digest));
+        let reply = out.try_recv().expect("Digest was not answered");
+        match serde_json::from_value(reply.body.message).unwrap() {
+            Payload::Reconcile {
+                values,
+                ack,
+                undecoded,
+            } => {
+                assert!(undecoded);
+                assert_eq!(ack, 0);
+                assert_eq!(
+                    values.iter().collect::<HashSet<_>>(),
+                    HashSet::from([7, 8, 9])
+                );
+            }
+            other => panic!("Expected a reconcile, got {:?}", other),
+        }
+
+        // Once n2 couldn't decode ours either, it gets a bigger digest
+        let undecoded = Payload::Reconcile {
+            values: Values::default(),
+            ack: 0,
+            undecoded: true,
+        };
+        let undecoded = serde_json::to_value(undecoded).unwrap();
+        runtime::dispatch(&mut node, &mut ctx, message("n2", 3, undecoded));
+        let sent = gossip(&mut node, &ctx, &mut out);
+        assert_eq!(sent.len(), 1);
+        match serde_json::from_value(sent[0].clone()).unwrap() {
+            Payload::Digest { table, .. } => assert_eq!(table.len(), 2 * MIN_DIGEST_CELLS),
+            other => panic!("Expected a digest, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_delta_gossip_follows_each_peers_schedule() {
+        let (rpc, mut out) = Rpc::new();
+        let mut ctx = Context::new(rpc);
+        let mut node = BroadcastNode::default();
+        for msg in [
+            message(
+                "c1",
+                1,
+                json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2"]}),
+            ),
+            message(
+                "c1",
+                2,
+                json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+            ),
+            message("c1", 3, json!({"type": "broadcast", "message": 10})),
+            message("c1", 4, json!({"type": "broadcast", "message": 11})),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+
+        // Nothing is acknowledged yet, so a lost delta is sent again
+        let first = json!({"type": "delta", "from": 0, "values": [10, 11], "ack": 0});
+        assert_eq!(gossip(&mut node, &ctx, &mut out), vec![first.clone()]);
+        assert_eq!(gossip(&mut node, &ctx, &mut out), vec![first]);
+
+        // Once n2 acknowledges both values, only newer ones are shipped
+        for msg in [
+            message(
+                "n2",
+                1,
+                json!({"type": "delta", "from": 0, "values": [20], "ack": 2}),
+            ),
+            message("c1", 5, json!({"type": "broadcast", "message": 12})),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+        assert_eq!(
+            gossip(&mut node, &ctx, &mut out),
+            vec![json!({"type": "delta", "from": 2, "values": [20, 12], "ack": 1})]
+        );
+
+        // A delta that skips part of n2's log is kept but not acknowledged
+        let gap = message(
+            "n2",
+            2,
+            json!({"type": "delta", "from": 3, "values": [40], "ack": 4}),
+        );
+        runtime::dispatch(&mut node, &mut ctx, gap);
+        assert_eq!(
+            gossip(&mut node, &ctx, &mut out),
+            vec![json!({"type": "delta", "from": 4, "values": [40], "ack": 1})]
+        );
+
+        // Once n2 has everything, it's left alone until there is news
+        let caught_up = message(
+            "n2",
+            3,
+            json!({"type": "delta", "from": 1, "values": [], "ack": 5}),
+        );
+        runtime::dispatch(&mut node, &mut ctx, caught_up);
+        for _ in 0..MAX_IDLE_PERIODS {
+            assert_eq!(
+                gossip(&mut node, &ctx, &mut out),
+                Vec::<serde_json::Value>::new()
+            );
+        }
+        let news = message("c1", 6, json!({"type": "broadcast", "message": 13}));
+        runtime::dispatch(&mut node, &mut ctx, news);
+        while out.try_recv().is_ok() {}
+        node.on_tick(&ctx);
+        let sent = out.try_recv().expect("New value was not sent right away");
+        assert_eq!(
+            sent.body.message,
+            json!({"type": "delta", "from": 5, "values": [13], "ack": 1})
+        );
+
+        // n2 hasn't acknowledged 13 since, so it's sent again with 14
+        node.config.redundancy = 1.0;
+        let news = message("c1", 7, json!({"type": "broadcast", "message": 14}));
+        runtime::dispatch(&mut node, &mut ctx, news);
+        assert_eq!(
+            gossip(&mut node, &ctx, &mut out),
+            vec![json!({"type": "delta", "from": 5, "values": [13, 14], "ack": 1})]
+        );
+
+        // With redundancy, what n2 hasn't acknowledged rides along with new
+        // values, but nothing it already has
+        for msg in [
+            message(
+                "n2",
+                4,
+                json!({"type": "delta", "from": 1, "values": [], "ack": 6}),
+            ),
+            message("c1", 8, json!({"type": "broadcast", "message": 15})),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+        assert_eq!(
+            gossip(&mut node, &ctx, &mut out),
+            vec![json!({"type": "delta", "from": 6, "values": [14, 15], "ack": 1})]
+        );
+    }
+
+    #[test]
+    fn test_pull_catches_up_quickly_after_an_idle_stretch() {
+        let config = GossipConfig {
+            peers: PeerSelection::Random,
+            exchange: Exchange::Pull,
+            max_payload: Some(2),
+            ..Default::default()
+        };
+        let period = config.period;
+        let mut sim = Simulation::new(9, Network::default(), 2, || {
+            BroadcastNode::new(BroadcastMode::Gossip, config.clone())
+        });
+
+        // n1 can't pull n0's values, nor acknowledge them, while they are
+        // apart. Every pull comes back empty, so they get as far apart as
+        // they can, even though n0 knows n1 is behind
+        sim.partition(&[&["n0"], &["n1"]]);
+        for message in 0..10 {
+            let reply: Option<Payload> = sim.call("n0", Payload::Broadcast { message }, TIMEOUT);
+            assert_eq!(reply, Some(Payload::BroadcastOk));
+        }
+        sim.run_for(period * MAX_IDLE_PERIODS * 2);
+        for (node, peer) in [("n0", "n1"), ("n1", "n0")] {
+            assert_eq!(sim.node(node).intervals[peer], period * MAX_IDLE_PERIODS);
+        }
+
+        // n1 only hears of n0's values when it next pulls, but from then on
+        // every reply with values brings the next pull forward
+        sim.heal();
+        let start = sim.now();
+        while sim.node("n1").messages.len() < 10 {
+            sim.run_for(period / TICKS_PER_PERIOD);
+            assert!(
+                sim.now() - start <= period * (MAX_IDLE_PERIODS + 2),
+                "Took too long to pull 10 values after idling"
+            );
+        }
+    }
+
+    #[test]
+    fn test_merkle_gossip_backs_off_once_in_sync() {
+        let (rpc, mut out) = Rpc::new();
+        let mut ctx = Context::new(rpc);
+        let config = GossipConfig {
+            exchange: Exchange::Merkle,
+            ..Default::default()
+        };
+        let mut node = BroadcastNode::new(BroadcastMode::Gossip, config);
+        for msg in [
+            message(
+                "c1",
+                1,
+                json!({"type": "init", "node_id": "n1", "node_ids": ["n1", "n2"]}),
+            ),
+            message(
+                "c1",
+                2,
+                json!({"type": "topology", "topology": {"n1": ["n2"], "n2": ["n1"]}}),
+            ),
+        ] {
+            runtime::dispatch(&mut node, &mut ctx, msg);
+        }
+        let root = json!({"type": "merkle_hashes", "nodes": [[{"level": 0, "index": 0}, 0]]});
+        assert_eq!(gossip(&mut node, &ctx, &mut out), vec![root.clone()]);
+
+        // n2's tree matches ours, so rounds with it get further apart
+        let in_sync = message("n2", 1, json!({"type": "merkle_hashes", "nodes": []}));
+        runtime::dispatch(&mut node, &mut ctx, in_sync);
+        let rounds: Vec<bool> = (0..15)
+            .map(|_| !gossip(&mut node, &ctx, &mut out).is_empty())
+            .collect();
+        assert_eq!(rounds.iter().filter(|sent| **sent).count(), 4);
+
+        // Until we learn something it may not have
+        let news = message("c1", 3, json!({"type": "broadcast", "message": 7}));
+        runtime::dispatch(&mut node, &mut ctx, news);
+        let sent = gossip(&mut node, &ctx, &mut out);
+        assert_eq!(sent.len(), 1);
+        assert_ne!(sent[0], root);
+    }
+
+    #[tokio::test]
+    async fn test_acked_broadcast_retries_until_acked() {
+        let (rpc, mut out) = Rpc::new();
+        let mut broadcast = AckedBroadcast::default();
+        broadcast.forward(&rpc, "n2", BTreeSet::from([1, 2]));
+
+        // Nobody answers the first attempt, so the same batch is sent again
+        let first = out.recv().await.expect("Batch was not sent");
+        let retry = out.recv().await.expect("Batch was not retried");
+        assert_eq!(first.body.message, retry.body.message);
+        assert_ne!(first.body.msg_id, retry.body.msg_id);
+
+        let ack = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: retry.body.msg_id,
+                message: json!({"type": "gossip_ok"}),
+            },
+        };
+        assert!(rpc.resolve(ack).is_none(), "Ack was not claimed");
+
+        broadcast.forward(&rpc, "n2", BTreeSet::from([3]));
+        let next = out.recv().await.expect("New value was not sent");
+        assert_eq!(
+            next.body.message,
+            json!({"type": "gossip", "has_seen": [3]})
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use tokio::time::Duration;
+
+use crate::encoding::Encoding;
+use crate::error::Error;
+use crate::runtime::DEFAULT_TICK;
+use crate::topology::TopologyStrategy;
+
+/// Who a node picks to gossip with each round.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerSelection {
+    /// Neighbours in the topology.
+    #[default]
+    Topology,
+    /// Any other node in the cluster.
+    Random,
+}
+
+impl FromStr for PeerSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "topology" => Ok(PeerSelection::Topology),
+            "random" => Ok(PeerSelection::Random),
+            other => Err(format!("Unknown peer selection: {}", other)),
+        }
+    }
+}
+
+/// Which way values flow in a round of gossip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Exchange {
+    /// We send the peer whatever it hasn't acknowledged yet.
+    #[default]
+    Push,
+    /// We tell the peer how much of its log we have and it sends back the rest.
+    Pull,
+    /// Like pull, but we also send the peer whatever it hasn't acknowledged.
+    PushPull,
+    /// We send the peer a fixed-size digest of everything we have, from which
+    /// it works out what each of us is missing.
+    Digest,
+    /// We compare Merkle trees of what we have with the peer, level by level,
+    /// and only repair the ranges that differ.
+    Merkle,
+}
+
+impl FromStr for Exchange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "push" => Ok(Exchange::Push),
+            "pull" => Ok(Exchange::Pull),
+            "push-pull" => Ok(Exchange::PushPull),
+            "digest" => Ok(Exchange::Digest),
+            "merkle" => Ok(Exchange::Merkle),
+            other => Err(format!("Unknown gossip exchange: {}", other)),
+        }
+    }
+}
+
+/// How often, how widely and how much nodes gossip. Every setting can be
+/// given as a command line flag, e.g. `--gossip-fan-out 3`, or as the
+/// matching `FESTROM_` environment variable, e.g. `FESTROM_GOSSIP_FAN_OUT=3`.
+/// Flags win over the environment.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GossipConfig {
+    /// `--gossip-period-ms`: time between two rounds of gossip. Broadcast
+    /// keeps one schedule per peer and backs off from peers with nothing new.
+    pub period: Duration,
+    /// `--gossip-jitter-ms`: up to this much is randomly added to each period,
+    /// so that nodes don't all gossip in lockstep.
+    pub jitter: Duration,
+    /// `--topology`: `maelstrom`, `star`, `tree:<k>`, `ring`, `small-world`
+    /// or `spanning-tree`.
+    pub topology: TopologyStrategy,
+    /// `--gossip-peers`: `topology` or `random`.
+    pub peers: PeerSelection,
+    /// `--gossip-exchange`: `push`, `pull`, `push-pull`, `digest`
+    /// or `merkle`.
+    pub exchange: Exchange,
+    /// `--gossip-fan-out`: how many peers to gossip with each round. All of
+    /// them when unset.
+    pub fan_out: Option<usize>,
+    /// `--gossip-redundancy`: values a neighbour hasn't acknowledged yet are
+    /// resent with new ones at this rate, to cover for lost gossip.
+    pub redundancy: f64,
+    /// `--gossip-max-payload`: the most values a single gossip message
+    /// carries. Whatever doesn't fit goes in later rounds. Unlimited when
+    /// unset.
+    pub max_payload: Option<usize>,
+    /// `--gossip-encoding`: `list` or `ranges`.
+    pub encoding: Encoding,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        GossipConfig {
+            period: DEFAULT_TICK,
+            jitter: Duration::ZERO,
+            topology: TopologyStrategy::Maelstrom,
+            peers: PeerSelection::Topology,
+            exchange: Exchange::Push,
+            fan_out: None,
+            // I'd only like to send 20% extra gossip - I haven't done much tuning on this though
+            redundancy: 0.2,
+            max_payload: None,
+            encoding: Encoding::List,
+        }
+    }
+}
+
+impl GossipConfig {
+    /// Reads the configuration from this process' arguments and environment.
+    pub fn load() -> Result<Self, Error> {
+        Self::parse(std::env::args().skip(1), |name| std::env::var(name).ok())
+    }
+
+    pub fn parse(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Error> {
+        let mut settings = Settings {
+            flags: parse_flags(args)?,
+            env,
+        };
+        let defaults = GossipConfig::default();
+        let config = GossipConfig {
+            period: settings
+                .get("gossip-period-ms")?
+                .map_or(defaults.period, Duration::from_millis),
+            jitter: settings
+                .get("gossip-jitter-ms")?
+                .map_or(defaults.jitter, Duration::from_millis),
+            topology: settings.get("topology")?.unwrap_or(defaults.topology),
+            peers: settings.get("gossip-peers")?.unwrap_or(defaults.peers),
+            exchange: settings
+                .get("gossip-exchange")?
+                .unwrap_or(defaults.exchange),
+            fan_out: settings.get("gossip-fan-out")?,
+            redundancy: settings
+                .get("gossip-redundancy")?
+                .unwrap_or(defaults.redundancy),
+            max_payload: settings.get("gossip-max-payload")?,
+            encoding: settings
+                .get("gossip-encoding")?
+                .unwrap_or(defaults.encoding),
+        };
+
+        if let Some(flag) = settings.flags.keys().next() {
+            return Err(Error::ConfigError(format!("Unknown flag --{}", flag)));
+        }
+        if config.period.is_zero() {
+            return Err(Error::ConfigError(
+                "The gossip period can't be 0".to_string(),
+            ));
+        }
+        if config.fan_out == Some(0) || config.max_payload == Some(0) {
+            return Err(Error::ConfigError(
+                "Gossip fan-out and max payload must be at least 1".to_string(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&config.redundancy) {
+            return Err(Error::ConfigError(
+                "Gossip redundancy must be between 0 and 1".to_string(),
+            ));
+        }
+        Ok(config)
+    }
+}
+
+/// Accepts `--name value` and `--name=value`.
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<HashMap<String, String>, Error> {
+    let mut flags = HashMap::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(Error::ConfigError(format!("Unexpected argument {}", arg)));
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| Error::ConfigError(format!("--{} needs a value", flag)))?;
+                (flag.to_string(), value)
+            }
+        };
+        flags.insert(name, value);
+    }
+    Ok(flags)
+}
+
+struct Settings<E> {
+    flags: HashMap<String, String>,
+    env: E,
+}
+
+impl<E: Fn(&str) -> Option<String>> Settings<E> {
+    /// Looks `name` up as a flag, then as its environment variable.
+    fn get<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, Error> {
+        let var = format!("FESTROM_{}", name.to_uppercase().replace('-', "_"));
+        let (source, value) = match self.flags.remove(name) {
+            Some(value) => (format!("--{}", name), value),
+            None => match (self.env)(&var) {
+                Some(value) => (var, value),
+                None => return Ok(None),
+            },
+        };
+        value
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::ConfigError(format!("Invalid value for {}: {}", source, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_flags_override_env() {
+        let env = |name: &str| match name {
+            "FESTROM_GOSSIP_PERIOD_MS" => Some("100".to_string()),
+            "FESTROM_GOSSIP_FAN_OUT" => Some("2".to_string()),
+            "FESTROM_TOPOLOGY" => Some("tree:3".to_string()),
+            _ => None,
+        };
+        let config = GossipConfig::parse(
+            args(&[
+                "--gossip-fan-out",
+                "3",
+                "--gossip-max-payload=50",
+                "--gossip-redundancy=0.5",
+                "--gossip-encoding",
+                "ranges",
+                "--gossip-exchange=push-pull",
+            ]),
+            env,
+        )
+        .expect("Failed to parse the config");
+        assert_eq!(
+            config,
+            GossipConfig {
+                period: Duration::from_millis(100),
+                fan_out: Some(3),
+                max_payload: Some(50),
+                redundancy: 0.5,
+                encoding: Encoding::Ranges,
+                exchange: Exchange::PushPull,
+                topology: TopologyStrategy::Tree(3),
+                ..Default::default()
+            }
+        );
+
+        assert_eq!(
+            GossipConfig::parse(vec![], |_| None).expect("Defaults should parse"),
+            GossipConfig::default()
+        );
+        for bad in [
+            &["--gossip-fan-out"][..],
+            &["--gossip-fan-out", "many"],
+            &["--gossip-fan-out", "0"],
+            &["--gossip-max-payload", "0"],
+            &["--gossip-redundancy", "2"],
+            &["--gossip-peers", "everyone"],
+            &["--gossip-speed", "fast"],
+            &["fast"],
+        ] {
+            assert!(
+                GossipConfig::parse(args(bad), |_| None).is_err(),
+                "{:?} should not parse",
+                bad
+            );
+        }
+    }
+}
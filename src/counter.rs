@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+use crate::kv::{Kv, KvError};
+use crate::runtime::{Context, Handler};
+use crate::Message;
+
+const COUNTER_KEY: &str = "counter";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+}
+
+/// Adds `delta` to the shared counter, retrying the compare-and-set until no
+/// other node has raced us to it.
+pub async fn add(kv: &Kv, delta: i64) -> Result<(), KvError> {
+    loop {
+        let current = kv.try_read::<_, i64>(COUNTER_KEY).await?.unwrap_or(0);
+        match kv.cas(COUNTER_KEY, current, current + delta, true).await {
+            Ok(()) => return Ok(()),
+            Err(KvError::PreconditionFailed { .. }) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the counter. A plain seq-kv read may be served from a stale state,
+/// so we first write a value nobody has written before: reads issued after
+/// that write have to observe everything ordered before it.
+pub async fn read(kv: &Kv, node_id: String) -> Result<i64, KvError> {
+    kv.write(format!("sync-{}", node_id), Ulid::new().to_string())
+        .await?;
+    Ok(kv.try_read(COUNTER_KEY).await?.unwrap_or(0))
+}
+
+/// The grow-only counter workload, kept in seq-kv.
+#[derive(Default)]
+pub struct CounterNode;
+
+impl Handler for CounterNode {
+    type Payload = Payload;
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        let rpc = ctx.rpc().clone();
+        match &msg.body.message {
+            Payload::Add { delta } => {
+                let delta = *delta;
+                tokio::spawn(async move {
+                    let added = add(&Kv::seq(rpc.clone()), delta).await;
+                    rpc.reply(&msg, added.map(|_| Payload::AddOk));
+                });
+            }
+            Payload::Read => {
+                let node_id = ctx.node_id().to_string();
+                tokio::spawn(async move {
+                    let value = read(&Kv::seq(rpc.clone()), node_id).await;
+                    rpc.reply(&msg, value.map(|value| Payload::ReadOk { value }));
+                });
+            }
+            _ => ctx.not_supported(&msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    use crate::rpc::Rpc;
+    use crate::MessageBody;
+
+    fn reply_to(rpc: &Rpc, request: &MessageBody<Value>, payload: Value) {
+        let reply = Message {
+            src: "seq-kv".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: request.msg_id,
+                message: payload,
+            },
+        };
+        assert!(rpc.resolve(reply).is_none(), "Reply was not claimed");
+    }
+
+    #[tokio::test]
+    async fn test_add_retries_failed_cas() {
+        let (rpc, mut out) = Rpc::new();
+        let kv = Kv::seq(rpc.clone());
+        let add = tokio::spawn(async move { add(&kv, 3).await });
+
+        let read = out.recv().await.expect("Missing read");
+        reply_to(
+            &rpc,
+            &read.body,
+            json!({"type": "error", "code": 20, "text": ""}),
+        );
+        let cas = out.recv().await.expect("Missing cas");
+        assert_eq!(cas.body.message["from"], json!(0));
+        assert_eq!(cas.body.message["to"], json!(3));
+        reply_to(
+            &rpc,
+            &cas.body,
+            json!({"type": "error", "code": 22, "text": ""}),
+        );
+
+        let read = out.recv().await.expect("Missing read after failed cas");
+        reply_to(&rpc, &read.body, json!({"type": "read_ok", "value": 5}));
+        let cas = out.recv().await.expect("Missing second cas");
+        assert_eq!(cas.body.message["from"], json!(5));
+        assert_eq!(cas.body.message["to"], json!(8));
+        reply_to(&rpc, &cas.body, json!({"type": "cas_ok"}));
+
+        add.await
+            .expect("Add task panicked")
+            .expect("Add should succeed");
+    }
+}
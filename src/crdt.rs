@@ -0,0 +1,594 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// A state-based CRDT: replicas converge by merging each other's states, in
+/// any order and any number of times.
+pub trait Crdt: Clone + Default + PartialEq + Serialize + DeserializeOwned {
+    /// Folds `other` into `self`. Has to be commutative, associative and
+    /// idempotent.
+    fn merge(&mut self, other: &Self);
+
+    /// The part of `self` that `since` is missing: merging it into `since`
+    /// gives the same as merging all of `self`. It is the default state when
+    /// `since` is already up to date.
+    fn delta(&self, since: &Self) -> Self;
+}
+
+/// What the set-like CRDTs can hold.
+pub trait Element: Ord + Clone + Serialize + DeserializeOwned {}
+
+impl<T: Ord + Clone + Serialize + DeserializeOwned> Element for T {}
+
+/// How many updates each replica has made that a state includes.
+pub type VersionVector = BTreeMap<String, u64>;
+
+fn merge_max(ours: &mut VersionVector, theirs: &VersionVector) {
+    for (node, count) in theirs {
+        let ours = ours.entry(node.clone()).or_default();
+        *ours = (*ours).max(*count);
+    }
+}
+
+fn newer_than(ours: &VersionVector, since: &VersionVector) -> VersionVector {
+    ours.iter()
+        .filter(|(node, count)| since.get(*node).is_none_or(|since| since < count))
+        .map(|(node, count)| (node.clone(), *count))
+        .collect()
+}
+
+/// A grow-only set.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GSet<T: Ord> {
+    items: BTreeSet<T>,
+}
+
+impl<T: Ord> Default for GSet<T> {
+    fn default() -> Self {
+        GSet {
+            items: BTreeSet::new(),
+        }
+    }
+}
+
+impl<T: Element> GSet<T> {
+    pub fn insert(&mut self, item: T) -> bool {
+        self.items.insert(item)
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.items.contains(item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter()
+    }
+}
+
+impl<T: Element> Crdt for GSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.items.extend(other.items.iter().cloned());
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        GSet {
+            items: self.items.difference(&since.items).cloned().collect(),
+        }
+    }
+}
+
+/// A set whose items can be removed once, after which they can never be
+/// added back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TwoPSet<T: Ord> {
+    added: GSet<T>,
+    removed: GSet<T>,
+}
+
+impl<T: Ord> Default for TwoPSet<T> {
+    fn default() -> Self {
+        TwoPSet {
+            added: GSet::default(),
+            removed: GSet::default(),
+        }
+    }
+}
+
+impl<T: Element> TwoPSet<T> {
+    pub fn insert(&mut self, item: T) {
+        self.added.insert(item);
+    }
+
+    /// Only items that are in the set can be removed.
+    pub fn remove(&mut self, item: &T) -> bool {
+        self.contains(item) && self.removed.insert(item.clone())
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.added.contains(item) && !self.removed.contains(item)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.added
+            .iter()
+            .filter(|item| !self.removed.contains(item))
+    }
+}
+
+impl<T: Element> Crdt for TwoPSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.added.merge(&other.added);
+        self.removed.merge(&other.removed);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        TwoPSet {
+            added: self.added.delta(&since.added),
+            removed: self.removed.delta(&since.removed),
+        }
+    }
+}
+
+/// Identifies one insertion into an `OrSet`: the replica that made it and
+/// how many updates that replica had made by then.
+pub type Dot = (String, u64);
+
+/// An observed-remove set. Removing an item only cancels the insertions the
+/// remover has seen, so an insertion concurrent with a removal wins.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct OrSet<T: Ord> {
+    /// Every insertion ever made, including removed ones.
+    adds: BTreeSet<(T, Dot)>,
+    /// Insertions that have been removed.
+    removed: BTreeSet<Dot>,
+    clock: VersionVector,
+}
+
+impl<T: Ord> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet {
+            adds: BTreeSet::new(),
+            removed: BTreeSet::new(),
+            clock: VersionVector::new(),
+        }
+    }
+}
+
+impl<T: Element> OrSet<T> {
+    /// Inserts `item` on behalf of the replica `node`.
+    pub fn insert(&mut self, node: &str, item: T) {
+        let count = self.clock.entry(node.to_string()).or_default();
+        *count += 1;
+        self.adds.insert((item, (node.to_string(), *count)));
+    }
+
+    pub fn remove(&mut self, item: &T) {
+        let dots = self
+            .adds
+            .iter()
+            .filter(|(added, _)| added == item)
+            .map(|(_, dot)| dot.clone());
+        self.removed.extend(dots);
+    }
+
+    pub fn contains(&self, item: &T) -> bool {
+        self.iter().any(|live| live == item)
+    }
+
+    /// Every item still in the set, each once, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let mut last = None;
+        self.adds
+            .iter()
+            .filter(|(_, dot)| !self.removed.contains(dot))
+            .map(|(item, _)| item)
+            .filter(move |item| last.replace(*item) != Some(*item))
+    }
+}
+
+impl<T: Element> Crdt for OrSet<T> {
+    fn merge(&mut self, other: &Self) {
+        self.adds.extend(other.adds.iter().cloned());
+        self.removed.extend(other.removed.iter().cloned());
+        merge_max(&mut self.clock, &other.clock);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        OrSet {
+            adds: self.adds.difference(&since.adds).cloned().collect(),
+            removed: self.removed.difference(&since.removed).cloned().collect(),
+            clock: newer_than(&self.clock, &since.clock),
+        }
+    }
+}
+
+/// A grow-only counter: each replica counts its own increments, and the
+/// value is their sum.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct GCounter {
+    counts: VersionVector,
+}
+
+impl GCounter {
+    pub fn increment(&mut self, node: &str, by: u64) {
+        *self.counts.entry(node.to_string()).or_default() += by;
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+}
+
+impl Crdt for GCounter {
+    fn merge(&mut self, other: &Self) {
+        merge_max(&mut self.counts, &other.counts);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        GCounter {
+            counts: newer_than(&self.counts, &since.counts),
+        }
+    }
+}
+
+/// A counter that also goes down, as a pair of grow-only counters.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+}
+
+impl PnCounter {
+    pub fn add(&mut self, node: &str, delta: i64) {
+        if delta >= 0 {
+            self.increments.increment(node, delta.unsigned_abs());
+        } else {
+            self.decrements.increment(node, delta.unsigned_abs());
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.increments.value() as i64 - self.decrements.value() as i64
+    }
+}
+
+impl Crdt for PnCounter {
+    fn merge(&mut self, other: &Self) {
+        self.increments.merge(&other.increments);
+        self.decrements.merge(&other.decrements);
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        PnCounter {
+            increments: self.increments.delta(&since.increments),
+            decrements: self.decrements.delta(&since.decrements),
+        }
+    }
+}
+
+/// A register where the latest write wins. Writes are ordered by timestamp,
+/// then by the id of the replica that made them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct LwwRegister<T> {
+    value: Option<T>,
+    stamp: (u64, String),
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister {
+            value: None,
+            stamp: (0, String::new()),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq + Serialize + DeserializeOwned> LwwRegister<T> {
+    /// Writes `value` unless the register already holds a later write.
+    pub fn set(&mut self, node: &str, timestamp: u64, value: T) -> bool {
+        let stamp = (timestamp, node.to_string());
+        let later = stamp > self.stamp;
+        if later {
+            self.value = Some(value);
+            self.stamp = stamp;
+        }
+        later
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        self.value.as_ref()
+    }
+}
+
+impl<T: Clone + PartialEq + Serialize + DeserializeOwned> Crdt for LwwRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        if other.stamp > self.stamp {
+            self.clone_from(other);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        if self.stamp > since.stamp {
+            self.clone()
+        } else {
+            LwwRegister::default()
+        }
+    }
+}
+
+/// A multi-value register: concurrent writes are all kept until a later
+/// write, which has seen them, replaces them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MvRegister<T> {
+    versions: Vec<(VersionVector, T)>,
+}
+
+impl<T> Default for MvRegister<T> {
+    fn default() -> Self {
+        MvRegister {
+            versions: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq + Serialize + DeserializeOwned> MvRegister<T> {
+    /// Writes `value` on behalf of the replica `node`, over every value it
+    /// has seen.
+    pub fn set(&mut self, node: &str, value: T) {
+        let mut version = VersionVector::new();
+        for (seen, _) in &self.versions {
+            merge_max(&mut version, seen);
+        }
+        *version.entry(node.to_string()).or_default() += 1;
+        self.versions = vec![(version, value)];
+    }
+
+    /// The current values, more than one after concurrent writes.
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.versions.iter().map(|(_, value)| value)
+    }
+}
+
+/// Whether every update `a` has seen, `b` has seen too, and then some.
+fn dominated(a: &VersionVector, b: &VersionVector) -> bool {
+    a != b
+        && a.iter()
+            .all(|(node, count)| b.get(node).is_some_and(|seen| seen >= count))
+}
+
+impl<T: Clone + PartialEq + Serialize + DeserializeOwned> Crdt for MvRegister<T> {
+    fn merge(&mut self, other: &Self) {
+        let mut versions = self.versions.clone();
+        for version in &other.versions {
+            if !versions.iter().any(|(seen, _)| *seen == version.0) {
+                versions.push(version.clone());
+            }
+        }
+        self.versions = versions
+            .iter()
+            .filter(|(a, _)| !versions.iter().any(|(b, _)| dominated(a, b)))
+            .cloned()
+            .collect();
+        // Replicas must agree on the order, not only on the values
+        self.versions.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        let mut merged = since.clone();
+        merged.merge(self);
+        if merged == *since {
+            MvRegister::default()
+        } else {
+            self.clone()
+        }
+    }
+}
+
+/// A map of CRDTs merges key by key, which turns for instance registers into
+/// a key/value store.
+impl<K: Element, V: Crdt> Crdt for BTreeMap<K, V> {
+    fn merge(&mut self, other: &Self) {
+        for (key, theirs) in other {
+            self.entry(key.clone()).or_default().merge(theirs);
+        }
+    }
+
+    fn delta(&self, since: &Self) -> Self {
+        self.iter()
+            .filter_map(|(key, ours)| {
+                let delta = match since.get(key) {
+                    Some(theirs) => ours.delta(theirs),
+                    None => ours.clone(),
+                };
+                (delta != V::default()).then(|| (key.clone(), delta))
+            })
+            .collect()
+    }
+}
+
+/// Most deltas kept waiting for an acknowledgement per peer. Older ones are
+/// forgotten, which only costs resending what they carried.
+const MAX_IN_FLIGHT: usize = 8;
+
+/// A CRDT as one replica holds it, along with what it knows each of its
+/// peers has, so that gossip only carries the deltas they are missing.
+#[derive(Default)]
+pub struct Replica<C> {
+    state: C,
+    /// Everything each peer is known to have, from what it sent us and what
+    /// it acknowledged.
+    known: HashMap<String, C>,
+    /// The latest deltas sent to each peer, by the sequence number their
+    /// acknowledgements will carry.
+    in_flight: HashMap<String, BTreeMap<u64, C>>,
+    next_seq: u64,
+}
+
+impl<C: Crdt> Replica<C> {
+    pub fn state(&self) -> &C {
+        &self.state
+    }
+
+    /// For local updates.
+    pub fn state_mut(&mut self) -> &mut C {
+        &mut self.state
+    }
+
+    /// The delta to send `peer` and the sequence number to acknowledge it
+    /// with, unless the peer is known to be up to date.
+    pub fn outgoing(&mut self, peer: &str) -> Option<(u64, C)> {
+        let delta = match self.known.get(peer) {
+            Some(known) => self.state.delta(known),
+            None => self.state.clone(),
+        };
+        if delta == C::default() {
+            return None;
+        }
+        self.next_seq += 1;
+        let in_flight = self.in_flight.entry(peer.to_string()).or_default();
+        in_flight.insert(self.next_seq, delta.clone());
+        if in_flight.len() > MAX_IN_FLIGHT {
+            in_flight.pop_first();
+        }
+        Some((self.next_seq, delta))
+    }
+
+    /// Merges what `peer` sent us, which it obviously has.
+    pub fn incoming(&mut self, peer: &str, delta: &C) {
+        self.state.merge(delta);
+        self.known.entry(peer.to_string()).or_default().merge(delta);
+    }
+
+    /// Records that `peer` got the delta sent as `seq`. Each delta covers
+    /// everything unacknowledged before it, so older ones are dropped too.
+    pub fn acknowledged(&mut self, peer: &str, seq: u64) {
+        let Some(in_flight) = self.in_flight.get_mut(peer) else {
+            return;
+        };
+        let Some(delta) = in_flight.remove(&seq) else {
+            return;
+        };
+        *in_flight = in_flight.split_off(&seq);
+        self.known
+            .entry(peer.to_string())
+            .or_default()
+            .merge(&delta);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Merges every pair of replicas both ways and checks they end up equal,
+    /// and that merging the same state again changes nothing.
+    fn assert_converges<C: Crdt + std::fmt::Debug>(replicas: &[C]) -> C {
+        let mut forward = C::default();
+        for replica in replicas {
+            forward.merge(replica);
+        }
+        let mut backward = C::default();
+        for replica in replicas.iter().rev() {
+            backward.merge(replica);
+            backward.merge(replica);
+        }
+        assert_eq!(forward, backward);
+        for replica in replicas {
+            let mut patched = replica.clone();
+            patched.merge(&forward.delta(replica));
+            assert_eq!(patched, forward, "Delta is missing part of the state");
+        }
+        forward
+    }
+
+    #[test]
+    fn test_concurrent_updates_converge() {
+        let (mut a, mut b) = (GSet::default(), GSet::default());
+        a.insert(1);
+        b.insert(2);
+        let set = assert_converges(&[a, b]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&1, &2]);
+
+        let (mut a, mut b) = (TwoPSet::default(), TwoPSet::default());
+        a.insert('x');
+        b.merge(&a);
+        assert!(b.remove(&'x'));
+        a.insert('y');
+        let set = assert_converges(&[a, b]);
+        assert_eq!(set.iter().collect::<Vec<_>>(), vec![&'y']);
+
+        // A removal only cancels the insertions it saw
+        let (mut a, mut b) = (OrSet::default(), OrSet::default());
+        a.insert("n1", 'x');
+        b.merge(&a);
+        b.remove(&'x');
+        a.insert("n1", 'x');
+        let set = assert_converges(&[a, b]);
+        assert!(set.contains(&'x'));
+
+        let (mut a, mut b) = (PnCounter::default(), PnCounter::default());
+        a.add("n1", 5);
+        a.add("n1", -2);
+        b.add("n2", -10);
+        let counter = assert_converges(&[a, b]);
+        assert_eq!(counter.value(), -7);
+
+        let (mut a, mut b) = (LwwRegister::default(), LwwRegister::default());
+        a.set("n1", 10, "old".to_string());
+        b.set("n2", 11, "new".to_string());
+        let register = assert_converges(&[a, b]);
+        assert_eq!(register.get().map(String::as_str), Some("new"));
+
+        let (mut a, mut b) = (MvRegister::default(), MvRegister::default());
+        a.set("n1", 1);
+        b.set("n2", 2);
+        let mut register = assert_converges(&[a, b]);
+        assert_eq!(register.values().count(), 2);
+        register.set("n1", 3);
+        assert_eq!(register.values().collect::<Vec<_>>(), vec![&3]);
+
+        let (mut a, mut b) = (BTreeMap::new(), BTreeMap::new());
+        a.entry("k".to_string())
+            .or_insert_with(GCounter::default)
+            .increment("n1", 1);
+        b.entry("k".to_string())
+            .or_insert_with(GCounter::default)
+            .increment("n2", 2);
+        let map = assert_converges(&[a, b]);
+        assert_eq!(map["k"].value(), 3);
+    }
+
+    #[test]
+    fn test_replica_sends_only_unacknowledged_deltas() {
+        let mut replica: Replica<GCounter> = Replica::default();
+        replica.state_mut().increment("n1", 1);
+        let (first, delta) = replica.outgoing("n2").expect("Nothing to send");
+        assert_eq!(delta.value(), 1);
+        // Unacknowledged deltas are sent again
+        let (second, _) = replica.outgoing("n2").expect("Delta was not resent");
+        assert!(second > first);
+        // An ack for an earlier delta still counts
+        replica.acknowledged("n2", first);
+        assert_eq!(replica.outgoing("n2"), None);
+
+        replica.state_mut().increment("n1", 2);
+        let (third, delta) = replica.outgoing("n2").expect("Nothing to send");
+        assert_eq!(delta.value(), 3);
+        // The ack for `second` comes too late to cover the new increment
+        replica.acknowledged("n2", second);
+        assert!(
+            replica.outgoing("n2").is_some(),
+            "Stale ack covered too much"
+        );
+        replica.acknowledged("n2", third);
+        assert_eq!(replica.outgoing("n2"), None);
+
+        // What a peer sends us, it has
+        let mut theirs = GCounter::default();
+        theirs.increment("n2", 5);
+        replica.incoming("n2", &theirs);
+        assert_eq!(replica.state().value(), 8);
+        assert_eq!(replica.outgoing("n2"), None);
+    }
+}
@@ -0,0 +1,156 @@
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+/// How gossiped values are written on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// One number per value, in the order they were learned.
+    #[default]
+    List,
+    /// Sorted inclusive ranges, which are much shorter for dense sets.
+    Ranges,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(Encoding::List),
+            "ranges" => Ok(Encoding::Ranges),
+            other => Err(format!("Unknown gossip encoding: {}", other)),
+        }
+    }
+}
+
+/// Most values a single batch may hold once its ranges are expanded.
+pub const MAX_VALUES: usize = 1 << 20;
+
+/// A batch of distinct values, as `[1, 2, 3, 7]` or as `[[1, 3], [7, 7]]`.
+/// Receivers understand both, whatever they send themselves. Batches with
+/// backwards ranges or more than `MAX_VALUES` values don't deserialize.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Values {
+    List(Vec<usize>),
+    Ranges(Vec<(usize, usize)>),
+}
+
+/// `Values` as they come off the wire, before they are checked.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawValues {
+    List(Vec<usize>),
+    Ranges(Vec<(usize, usize)>),
+}
+
+impl<'de> Deserialize<'de> for Values {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = match RawValues::deserialize(deserializer)? {
+            RawValues::List(values) => Values::List(values),
+            RawValues::Ranges(ranges) => {
+                if let Some((start, end)) = ranges.iter().find(|(start, end)| start > end) {
+                    return Err(de::Error::custom(format!(
+                        "range [{}, {}] ends before it starts",
+                        start, end
+                    )));
+                }
+                Values::Ranges(ranges)
+            }
+        };
+        if values.len() > MAX_VALUES {
+            return Err(de::Error::custom(format!(
+                "more than {} values in one batch",
+                MAX_VALUES
+            )));
+        }
+        Ok(values)
+    }
+}
+
+impl Default for Values {
+    fn default() -> Self {
+        Values::List(Vec::new())
+    }
+}
+
+impl Values {
+    pub fn encode(values: Vec<usize>, encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::List => Values::List(values),
+            Encoding::Ranges => {
+                let mut values = values;
+                values.sort_unstable();
+                values.dedup();
+                let mut ranges: Vec<(usize, usize)> = Vec::new();
+                for value in values {
+                    match ranges.last_mut() {
+                        Some((_, end)) if *end + 1 == value => *end = value,
+                        _ => ranges.push((value, value)),
+                    }
+                }
+                Values::Ranges(ranges)
+            }
+        }
+    }
+
+    /// How many values there are, not how many entries they take up.
+    pub fn len(&self) -> usize {
+        match self {
+            Values::List(values) => values.len(),
+            Values::Ranges(ranges) => ranges
+                .iter()
+                .map(|(start, end)| end.checked_sub(*start).map_or(0, |n| n.saturating_add(1)))
+                .fold(0, usize::saturating_add),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = usize> + '_> {
+        match self {
+            Values::List(values) => Box::new(values.iter().copied()),
+            Values::Ranges(ranges) => {
+                Box::new(ranges.iter().flat_map(|(start, end)| *start..=*end))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_ranges_round_trip() {
+        let values = vec![7, 3, 1, 2, 9, 8, 12];
+        let ranges = Values::encode(values.clone(), Encoding::Ranges);
+        assert_eq!(
+            serde_json::to_value(&ranges).expect("Failed to serialize"),
+            json!([[1, 3], [7, 9], [12, 12]])
+        );
+        assert_eq!(ranges.len(), values.len());
+
+        let decoded: Values =
+            serde_json::from_value(json!([[1, 3], [7, 9], [12, 12]])).expect("Failed to parse");
+        let mut sorted = values.clone();
+        sorted.sort();
+        assert_eq!(decoded.iter().collect::<Vec<_>>(), sorted);
+
+        let list: Values = serde_json::from_value(json!(values)).expect("Failed to parse");
+        assert_eq!(list, Values::encode(values, Encoding::List));
+        assert!(Values::default().is_empty());
+
+        for bad in [
+            json!([[3, 1]]),
+            json!([[0, usize::MAX]]),
+            json!([[0, usize::MAX], [0, usize::MAX]]),
+        ] {
+            assert!(serde_json::from_value::<Values>(bad).is_err());
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use crate::rpc::RpcError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Standard IO Error: {0}")]
+    IoError(#[from] std::io::Error),
+    #[error("Serde JSON error: {0}")]
+    SerdeError(#[from] serde_json::Error),
+    #[error("RPC error: {0}")]
+    RpcError(#[from] RpcError),
+    #[error("Malformed message {line}: {source}")]
+    MalformedMessage {
+        line: String,
+        source: serde_json::Error,
+    },
+    #[error("Configuration error: {0}")]
+    ConfigError(String),
+}
+
+/// Error codes defined by the Maelstrom protocol. Codes not defined by
+/// Maelstrom are kept as `Custom` so that they survive a round trip.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "u64", into = "u64")]
+pub enum ErrorCode {
+    /// The requested operation could not be completed in time.
+    Timeout,
+    /// A client asked for a node that doesn't exist.
+    NodeNotFound,
+    /// The message type or operation is not supported by this node.
+    NotSupported,
+    /// The operation definitely can't be performed right now.
+    TemporarilyUnavailable,
+    /// The request was malformed in some way.
+    MalformedRequest,
+    /// Indefinite general-purpose failure.
+    Crash,
+    /// Definite general-purpose failure.
+    Abort,
+    KeyDoesNotExist,
+    KeyAlreadyExists,
+    /// A compare-and-set style precondition didn't hold.
+    PreconditionFailed,
+    /// The transaction was aborted because of a conflict with another one.
+    TxnConflict,
+    Custom(u64),
+}
+
+impl ErrorCode {
+    /// Whether the error guarantees that the operation did not take place.
+    /// Indefinite errors (timeouts, crashes and anything we don't know about)
+    /// leave the outcome unknown.
+    pub fn is_definite(&self) -> bool {
+        !matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Custom(_)
+        )
+    }
+}
+
+/// An error we can report back to a client as a Maelstrom `error` message.
+pub trait MaelstromError: std::fmt::Display {
+    fn code(&self) -> ErrorCode;
+}
+
+impl From<u64> for ErrorCode {
+    fn from(code: u64) -> Self {
+        use ErrorCode::*;
+        match code {
+            0 => Timeout,
+            1 => NodeNotFound,
+            10 => NotSupported,
+            11 => TemporarilyUnavailable,
+            12 => MalformedRequest,
+            13 => Crash,
+            14 => Abort,
+            20 => KeyDoesNotExist,
+            21 => KeyAlreadyExists,
+            22 => PreconditionFailed,
+            30 => TxnConflict,
+            code => Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u64 {
+    fn from(code: ErrorCode) -> Self {
+        use ErrorCode::*;
+        match code {
+            Timeout => 0,
+            NodeNotFound => 1,
+            NotSupported => 10,
+            TemporarilyUnavailable => 11,
+            MalformedRequest => 12,
+            Crash => 13,
+            Abort => 14,
+            KeyDoesNotExist => 20,
+            KeyAlreadyExists => 21,
+            PreconditionFailed => 22,
+            TxnConflict => 30,
+            Custom(code) => code,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use ErrorCode::*;
+        let name = match self {
+            Timeout => "timeout",
+            NodeNotFound => "node-not-found",
+            NotSupported => "not-supported",
+            TemporarilyUnavailable => "temporarily-unavailable",
+            MalformedRequest => "malformed-request",
+            Crash => "crash",
+            Abort => "abort",
+            KeyDoesNotExist => "key-does-not-exist",
+            KeyAlreadyExists => "key-already-exists",
+            PreconditionFailed => "precondition-failed",
+            TxnConflict => "txn-conflict",
+            Custom(code) => return write!(f, "custom error {}", code),
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,8 @@
+/// SplitMix64's finaliser: a cheap, well-spread hash of a 64-bit value that
+/// is the same on every node, unlike the standard library's hashers.
+pub fn mix(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
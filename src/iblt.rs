@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+
+use crate::hash::mix;
+
+/// Cells each value is added to. The table is split in as many parts, one
+/// per hash, so a value never lands twice in the same cell.
+const HASHES: usize = 3;
+
+/// An invertible Bloom lookup table: a summary of a set of values whose size
+/// only depends on how many differences it should be able to recover.
+/// Subtracting the tables of two sets and decoding the result yields the
+/// values each set has that the other doesn't, as long as there are not many
+/// more of them than about two thirds of the cells.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Iblt {
+    cells: Vec<Cell>,
+}
+
+/// How many values were added, the XOR of those values and the XOR of their
+/// checksums.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Cell(i64, u64, u32);
+
+impl Cell {
+    fn toggle(&mut self, key: u64, count: i64) {
+        self.0 += count;
+        self.1 ^= key;
+        self.2 ^= checksum(key);
+    }
+
+    /// Whether the cell holds exactly one value, and on which side.
+    fn pure(&self) -> Option<(u64, i64)> {
+        matches!(self.0, 1 | -1)
+            .then_some((self.1, self.0))
+            .filter(|(key, _)| self.2 == checksum(*key))
+    }
+}
+
+impl Iblt {
+    /// An empty table of at least `cells` cells.
+    pub fn new(cells: usize) -> Self {
+        let cells = cells.div_ceil(HASHES).max(1) * HASHES;
+        Iblt {
+            cells: vec![Cell::default(); cells],
+        }
+    }
+
+    pub fn from_values(cells: usize, values: impl IntoIterator<Item = usize>) -> Self {
+        let mut table = Iblt::new(cells);
+        for value in values {
+            table.insert(value);
+        }
+        table
+    }
+
+    /// How many cells the table has, which is not how many values it holds.
+    pub fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    pub fn insert(&mut self, value: usize) {
+        self.toggle(value as u64, 1);
+    }
+
+    /// The table of the values in `self` but not in `other` and the other way
+    /// round, if both tables have the same size.
+    pub fn subtract(&self, other: &Iblt) -> Option<Iblt> {
+        if self.len() != other.len() {
+            return None;
+        }
+        let cells = self
+            .cells
+            .iter()
+            .zip(&other.cells)
+            .map(|(ours, theirs)| Cell(ours.0 - theirs.0, ours.1 ^ theirs.1, ours.2 ^ theirs.2))
+            .collect();
+        Some(Iblt { cells })
+    }
+
+    /// Splits a difference of two tables back into the values only the first
+    /// one had and those only the second had. Fails when there are too many
+    /// differences for the size of the table.
+    pub fn decode(mut self) -> Option<(Vec<usize>, Vec<usize>)> {
+        let (mut ours, mut theirs) = (Vec::new(), Vec::new());
+        // Taking a pure cell's value out can leave other cells pure
+        let mut pending: Vec<usize> = (0..self.len()).collect();
+        while let Some(index) = pending.pop() {
+            let Some((key, count)) = self.cells[index].pure() else {
+                continue;
+            };
+            if count > 0 {
+                ours.push(key as usize);
+            } else {
+                theirs.push(key as usize);
+            }
+            pending.extend(self.toggle(key, -count));
+        }
+        self.cells
+            .iter()
+            .all(|cell| *cell == Cell::default())
+            .then_some((ours, theirs))
+    }
+
+    /// Adds `count` copies of `key` and returns the cells it went in.
+    fn toggle(&mut self, key: u64, count: i64) -> [usize; HASHES] {
+        let part = self.len() / HASHES;
+        let mut indices = [0; HASHES];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = i * part + (mix(key ^ mix(i as u64 + 1)) % part as u64) as usize;
+            self.cells[*index].toggle(key, count);
+        }
+        indices
+    }
+}
+
+fn checksum(key: u64) -> u32 {
+    mix(key ^ 0x5bd1_e995) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_recovers_the_difference() {
+        let ours = Iblt::from_values(30, (0..1000).chain([2000, 2001]));
+        let theirs = Iblt::from_values(30, (3..1000).chain([3000]));
+        let (mut only_ours, only_theirs) = ours
+            .subtract(&theirs)
+            .and_then(Iblt::decode)
+            .expect("Failed to decode a small difference");
+        only_ours.sort();
+        assert_eq!(only_ours, vec![0, 1, 2, 2000, 2001]);
+        assert_eq!(only_theirs, vec![3000]);
+
+        // Far more differences than cells can't be told apart
+        let empty = Iblt::new(30);
+        assert_eq!(ours.subtract(&empty).and_then(Iblt::decode), None);
+        assert_eq!(ours.subtract(&Iblt::new(60)), None);
+    }
+}
@@ -0,0 +1,385 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use crate::error::MaelstromError;
+use crate::kv::{Kv, KvError};
+use crate::runtime::{Context, Handler};
+use crate::Message;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Send {
+        key: String,
+        msg: usize,
+    },
+    SendOk {
+        offset: usize,
+    },
+    Poll {
+        offsets: HashMap<String, usize>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(usize, usize)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, usize>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, usize>,
+    },
+}
+
+/// Most messages a single poll returns per key.
+const POLL_LIMIT: usize = 100;
+
+/// Per-key append-only logs, keyed by offset, along with the offsets clients
+/// have committed for each key. Offsets we haven't seen yet leave gaps, and
+/// polls stop at the first one so that they never skip a message.
+#[derive(Debug, Default)]
+pub struct Log {
+    entries: HashMap<String, BTreeMap<usize, usize>>,
+    committed: HashMap<String, usize>,
+}
+
+impl Log {
+    /// Appends `msg` to `key`'s log and returns the offset it was given.
+    pub fn append(&mut self, key: &str, msg: usize) -> usize {
+        let entries = self.entries.entry(key.to_string()).or_default();
+        let offset = entries.keys().next_back().map_or(0, |last| last + 1);
+        entries.insert(offset, msg);
+        offset
+    }
+
+    /// Records `msg` as the message at `offset` of `key`'s log.
+    pub fn insert(&mut self, key: &str, offset: usize, msg: usize) {
+        self.entries
+            .entry(key.to_string())
+            .or_default()
+            .insert(offset, msg);
+    }
+
+    pub fn get(&self, key: &str, offset: usize) -> Option<usize> {
+        self.entries.get(key)?.get(&offset).copied()
+    }
+
+    /// Returns `[offset, msg]` pairs for `key` from `offset` up to the first
+    /// gap.
+    pub fn poll(&self, key: &str, offset: usize) -> Vec<(usize, usize)> {
+        self.entries
+            .get(key)
+            .map(|entries| {
+                entries
+                    .range(offset..)
+                    .zip(offset..)
+                    .take_while(|((&have, _), want)| have == *want)
+                    .map(|((&offset, &msg), _)| (offset, msg))
+                    .take(POLL_LIMIT)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Records `offset` as committed for `key`. Commits never move backwards.
+    pub fn commit(&mut self, key: &str, offset: usize) {
+        let committed = self.committed.entry(key.to_string()).or_default();
+        *committed = offset.max(*committed);
+    }
+
+    pub fn committed(&self, key: &str) -> Option<usize> {
+        self.committed.get(key).copied()
+    }
+}
+
+/// The kafka workload. A node on its own serves everything from its local
+/// `Log`; in a cluster, offsets come from a per-key counter in lin-kv, each
+/// message is stored under its own lin-kv key, and the local `Log` only caches
+/// the messages we've seen.
+#[derive(Clone, Default)]
+pub struct Kafka {
+    log: Arc<Mutex<Log>>,
+    replicated: bool,
+}
+
+impl Kafka {
+    /// Switches to lin-kv backed logs once we know we aren't alone.
+    pub fn set_replicated(&mut self, replicated: bool) {
+        self.replicated = replicated;
+    }
+
+    fn log(&self) -> std::sync::MutexGuard<'_, Log> {
+        self.log.lock().expect("Kafka log lock poisoned")
+    }
+
+    pub async fn send(self, kv: Kv, key: String, msg: usize) -> Result<usize, KvError> {
+        if !self.replicated {
+            return Ok(self.log().append(&key, msg));
+        }
+        let offset = loop {
+            let current: Option<usize> = kv.try_read(offset_key(&key)).await?;
+            let offset = current.unwrap_or_default();
+            match kv
+                .cas(offset_key(&key), current, Some(offset + 1), true)
+                .await
+            {
+                Ok(()) => break offset,
+                Err(KvError::PreconditionFailed { .. }) => continue,
+                Err(err) => return Err(err),
+            }
+        };
+        // Polls can't get past this offset until the message is there, so a
+        // write that may not have happened is tried again
+        loop {
+            match kv.write(msg_key(&key, offset), msg).await {
+                Ok(()) => break,
+                Err(err) if !err.code().is_definite() => {
+                    warn!(%err, key, offset, "Retrying a kafka message write");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.log().insert(&key, offset, msg);
+        Ok(offset)
+    }
+
+    pub async fn poll(
+        self,
+        kv: Kv,
+        offsets: HashMap<String, usize>,
+    ) -> Result<HashMap<String, Vec<(usize, usize)>>, KvError> {
+        let mut msgs = HashMap::new();
+        for (key, offset) in offsets {
+            let mut polled = self.log().poll(&key, offset);
+            if self.replicated && polled.len() < POLL_LIMIT {
+                // Somebody else may have appended past what we've cached
+                let end: Option<usize> = kv.try_read(offset_key(&key)).await?;
+                let end = end.unwrap_or_default().min(offset + POLL_LIMIT);
+                for next in offset + polled.len()..end {
+                    let cached = self.log().get(&key, next);
+                    let msg = match cached {
+                        Some(msg) => msg,
+                        // The sender has taken the offset but not written
+                        // the message yet
+                        None => match kv.try_read(msg_key(&key, next)).await? {
+                            Some(msg) => msg,
+                            None => break,
+                        },
+                    };
+                    self.log().insert(&key, next, msg);
+                    polled.push((next, msg));
+                }
+            }
+            msgs.insert(key, polled);
+        }
+        Ok(msgs)
+    }
+
+    pub async fn commit_offsets(
+        self,
+        kv: Kv,
+        offsets: HashMap<String, usize>,
+    ) -> Result<(), KvError> {
+        for (key, offset) in offsets {
+            if self.replicated {
+                loop {
+                    let current: Option<usize> = kv.try_read(commit_key(&key)).await?;
+                    if current >= Some(offset) {
+                        break;
+                    }
+                    match kv.cas(commit_key(&key), current, Some(offset), true).await {
+                        Ok(()) => break,
+                        Err(KvError::PreconditionFailed { .. }) => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+            }
+            self.log().commit(&key, offset);
+        }
+        Ok(())
+    }
+
+    pub async fn list_committed_offsets(
+        self,
+        kv: Kv,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, usize>, KvError> {
+        let mut offsets = HashMap::new();
+        for key in keys {
+            let committed = if self.replicated {
+                kv.try_read(commit_key(&key)).await?
+            } else {
+                self.log().committed(&key)
+            };
+            if let Some(offset) = committed {
+                offsets.insert(key, offset);
+            }
+        }
+        Ok(offsets)
+    }
+}
+
+/// Serves the kafka workload's messages with a shared `Kafka`.
+#[derive(Default)]
+pub struct KafkaNode {
+    kafka: Kafka,
+}
+
+impl Handler for KafkaNode {
+    type Payload = Payload;
+
+    fn init(&mut self, ctx: &Context) {
+        self.kafka.set_replicated(ctx.node_ids().len() > 1);
+    }
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        use Payload::*;
+
+        let (rpc, kafka) = (ctx.rpc().clone(), self.kafka.clone());
+        let kv = Kv::lin(rpc.clone());
+        match &msg.body.message {
+            Send { key, msg: value } => {
+                let (key, value) = (key.clone(), *value);
+                tokio::spawn(async move {
+                    let sent = kafka.send(kv, key, value).await;
+                    rpc.reply(&msg, sent.map(|offset| SendOk { offset }));
+                });
+            }
+            Poll { offsets } => {
+                let offsets = offsets.clone();
+                tokio::spawn(async move {
+                    let polled = kafka.poll(kv, offsets).await;
+                    rpc.reply(&msg, polled.map(|msgs| PollOk { msgs }));
+                });
+            }
+            CommitOffsets { offsets } => {
+                let offsets = offsets.clone();
+                tokio::spawn(async move {
+                    let committed = kafka.commit_offsets(kv, offsets).await;
+                    rpc.reply(&msg, committed.map(|_| CommitOffsetsOk));
+                });
+            }
+            ListCommittedOffsets { keys } => {
+                let keys = keys.clone();
+                tokio::spawn(async move {
+                    let offsets = kafka.list_committed_offsets(kv, keys).await;
+                    rpc.reply(
+                        &msg,
+                        offsets.map(|offsets| ListCommittedOffsetsOk { offsets }),
+                    );
+                });
+            }
+            _ => ctx.not_supported(&msg),
+        }
+    }
+}
+
+fn offset_key(key: &str) -> String {
+    format!("offset-{}", key)
+}
+
+fn msg_key(key: &str, offset: usize) -> String {
+    format!("msg-{}/{}", key, offset)
+}
+
+fn commit_key(key: &str) -> String {
+    format!("commit-{}", key)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::rpc::Rpc;
+    use crate::MessageBody;
+
+    fn reply_to(rpc: &Rpc, request: &MessageBody<Value>, payload: Value) {
+        let reply = Message {
+            src: "lin-kv".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: request.msg_id,
+                message: payload,
+            },
+        };
+        assert!(rpc.resolve(reply).is_none(), "Reply was not claimed");
+    }
+
+    #[test]
+    fn test_log_offsets_and_commits() {
+        let mut log = Log::default();
+        assert_eq!(log.append("k1", 10), 0);
+        assert_eq!(log.append("k1", 11), 1);
+        assert_eq!(log.append("k2", 20), 0);
+        assert_eq!(log.append("k1", 12), 2);
+
+        assert_eq!(log.poll("k1", 1), vec![(1, 11), (2, 12)]);
+        assert_eq!(log.poll("k2", 1), vec![]);
+        assert_eq!(log.poll("k3", 0), vec![]);
+
+        log.insert("k2", 2, 22);
+        assert_eq!(log.poll("k2", 0), vec![(0, 20)]);
+        log.insert("k2", 1, 21);
+        assert_eq!(log.poll("k2", 0), vec![(0, 20), (1, 21), (2, 22)]);
+
+        log.commit("k1", 2);
+        log.commit("k1", 1);
+        assert_eq!(log.committed("k1"), Some(2));
+        assert_eq!(log.committed("k2"), None);
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_a_lost_message_write() {
+        let (rpc, mut out) = Rpc::new();
+        let kv = Kv::lin(rpc.clone());
+        let mut kafka = Kafka::default();
+        kafka.set_replicated(true);
+        let send = tokio::spawn(kafka.send(kv.clone(), "k1".to_string(), 7));
+
+        let read = out.recv().await.expect("Missing offset read");
+        assert_eq!(read.body.message["key"], json!("offset-k1"));
+        reply_to(
+            &rpc,
+            &read.body,
+            json!({"type": "error", "code": 20, "text": ""}),
+        );
+        let cas = out.recv().await.expect("Missing offset cas");
+        assert_eq!(cas.body.message["to"], json!(1));
+        reply_to(&rpc, &cas.body, json!({"type": "cas_ok"}));
+
+        // The first write times out, so it's sent again
+        for reply in [
+            json!({"type": "error", "code": 0, "text": "timed out"}),
+            json!({"type": "write_ok"}),
+        ] {
+            let write = out.recv().await.expect("Missing message write");
+            assert_eq!(write.body.message["key"], json!("msg-k1/0"));
+            assert_eq!(write.body.message["value"], json!(7));
+            reply_to(&rpc, &write.body, reply);
+        }
+        let offset = send.await.expect("Send task panicked");
+        assert_eq!(offset.expect("Send should succeed"), 0);
+
+        // Another node finds the message in lin-kv
+        let mut other = Kafka::default();
+        other.set_replicated(true);
+        let poll = tokio::spawn(other.poll(kv, HashMap::from([("k1".to_string(), 0)])));
+        let read = out.recv().await.expect("Missing offset read");
+        reply_to(&rpc, &read.body, json!({"type": "read_ok", "value": 1}));
+        let read = out.recv().await.expect("Missing message read");
+        assert_eq!(read.body.message["key"], json!("msg-k1/0"));
+        reply_to(&rpc, &read.body, json!({"type": "read_ok", "value": 7}));
+        let msgs = poll.await.expect("Poll task panicked");
+        assert_eq!(msgs.expect("Poll should succeed")["k1"], vec![(0, 7)]);
+    }
+}
@@ -0,0 +1,268 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::error::{ErrorCode, MaelstromError};
+use crate::rpc::{Rpc, RpcError};
+
+/// Requests and replies of Maelstrom's key/value and timestamp services.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+    Ts,
+    TsOk {
+        ts: u64,
+    },
+}
+
+/// The key/value services Maelstrom runs alongside the nodes under test.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Service {
+    /// Linearizable key/value store.
+    Lin,
+    /// Sequentially consistent key/value store.
+    Seq,
+    /// Last-writer-wins key/value store.
+    Lww,
+}
+
+impl Service {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Service::Lin => "lin-kv",
+            Service::Seq => "seq-kv",
+            Service::Lww => "lww-kv",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KvError {
+    #[error("{service} has no key {key}")]
+    KeyDoesNotExist { service: &'static str, key: String },
+    #[error("{service} rejected the cas on {key}: {text}")]
+    PreconditionFailed {
+        service: &'static str,
+        key: String,
+        text: String,
+    },
+    #[error("{0}")]
+    Rpc(#[from] RpcError),
+}
+
+impl MaelstromError for KvError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            KvError::KeyDoesNotExist { .. } => ErrorCode::KeyDoesNotExist,
+            KvError::PreconditionFailed { .. } => ErrorCode::PreconditionFailed,
+            KvError::Rpc(err) => err.code(),
+        }
+    }
+}
+
+/// Typed client for one of Maelstrom's key/value services.
+#[derive(Clone)]
+pub struct Kv {
+    rpc: Rpc,
+    service: Service,
+}
+
+impl Kv {
+    pub fn new(rpc: Rpc, service: Service) -> Self {
+        Kv { rpc, service }
+    }
+
+    pub fn lin(rpc: Rpc) -> Self {
+        Kv::new(rpc, Service::Lin)
+    }
+
+    pub fn seq(rpc: Rpc) -> Self {
+        Kv::new(rpc, Service::Seq)
+    }
+
+    pub fn lww(rpc: Rpc) -> Self {
+        Kv::new(rpc, Service::Lww)
+    }
+
+    async fn call<K: Serialize>(&self, key: &K, payload: Payload) -> Result<Payload, KvError> {
+        let service = self.service.name();
+        let key = || json!(key).to_string();
+        match self.rpc.rpc(service.to_string(), payload).await {
+            Ok(reply) => Ok(reply),
+            Err(RpcError::Remote {
+                code: ErrorCode::KeyDoesNotExist,
+                ..
+            }) => Err(KvError::KeyDoesNotExist {
+                service,
+                key: key(),
+            }),
+            Err(RpcError::Remote {
+                code: ErrorCode::PreconditionFailed,
+                text,
+                ..
+            }) => Err(KvError::PreconditionFailed {
+                service,
+                key: key(),
+                text,
+            }),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub async fn read<K: Serialize, V: DeserializeOwned>(&self, key: K) -> Result<V, KvError> {
+        let reply = self.call(&key, Payload::Read { key: json!(key) }).await?;
+        let unexpected = |reply: Payload| {
+            KvError::Rpc(RpcError::Unexpected {
+                dest: self.service.name().to_string(),
+                reply: json!(reply),
+            })
+        };
+        match reply {
+            Payload::ReadOk { value } => serde_json::from_value(value.clone())
+                .map_err(|_| unexpected(Payload::ReadOk { value })),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Like `read`, but a key nobody has written yet reads as `None`.
+    pub async fn try_read<K: Serialize, V: DeserializeOwned>(
+        &self,
+        key: K,
+    ) -> Result<Option<V>, KvError> {
+        match self.read(key).await {
+            Ok(value) => Ok(Some(value)),
+            Err(KvError::KeyDoesNotExist { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub async fn write<K: Serialize, V: Serialize>(&self, key: K, value: V) -> Result<(), KvError> {
+        self.call(
+            &key,
+            Payload::Write {
+                key: json!(key),
+                value: json!(value),
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Replaces `key`'s value with `to` if it currently holds `from`. With
+    /// `create_if_not_exists`, a missing key is created with `to` instead of
+    /// failing with `KeyDoesNotExist`.
+    pub async fn cas<K: Serialize, V: Serialize>(
+        &self,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    ) -> Result<(), KvError> {
+        self.call(
+            &key,
+            Payload::Cas {
+                key: json!(key),
+                from: json!(from),
+                to: json!(to),
+                create_if_not_exists,
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
+/// Client for Maelstrom's `lin-tso` timestamp oracle.
+#[derive(Clone)]
+pub struct Tso {
+    rpc: Rpc,
+}
+
+impl Tso {
+    pub const NAME: &'static str = "lin-tso";
+
+    pub fn new(rpc: Rpc) -> Self {
+        Tso { rpc }
+    }
+
+    /// Returns a timestamp greater than any the oracle has handed out before.
+    pub async fn ts(&self) -> Result<u64, KvError> {
+        match self.rpc.rpc(Self::NAME.to_string(), Payload::Ts).await? {
+            Payload::TsOk { ts } => Ok(ts),
+            other => Err(KvError::Rpc(RpcError::Unexpected {
+                dest: Self::NAME.to_string(),
+                reply: json!(other),
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message, MessageBody, Protocol};
+
+    #[tokio::test]
+    async fn test_error_replies_are_typed() {
+        let (rpc, mut out) = Rpc::new();
+        let kv = Kv::lin(rpc.clone());
+
+        let replies = [
+            (ErrorCode::KeyDoesNotExist, "not found"),
+            (ErrorCode::PreconditionFailed, "expected 1, had 2"),
+            (ErrorCode::TemporarilyUnavailable, "try again"),
+        ];
+        for (code, text) in replies {
+            let cas = tokio::spawn({
+                let kv = kv.clone();
+                async move { kv.cas("k", 1, 2, false).await }
+            });
+            let sent = out.recv().await.expect("Cas was not sent");
+            assert_eq!(sent.dest, "lin-kv");
+            let reply = Message {
+                src: "lin-kv".to_string(),
+                dest: "n1".to_string(),
+                body: MessageBody {
+                    msg_id: Some(1),
+                    in_reply_to: sent.body.msg_id,
+                    message: json!(Protocol::Error {
+                        code,
+                        text: text.to_string(),
+                    }),
+                },
+            };
+            assert!(rpc.resolve(reply).is_none(), "Reply was not claimed");
+
+            let err = cas.await.expect("Cas task panicked").unwrap_err();
+            assert_eq!(err.code(), code);
+            match code {
+                ErrorCode::KeyDoesNotExist => {
+                    assert!(matches!(err, KvError::KeyDoesNotExist { .. }))
+                }
+                ErrorCode::PreconditionFailed => {
+                    assert!(matches!(err, KvError::PreconditionFailed { .. }))
+                }
+                _ => assert!(matches!(err, KvError::Rpc(RpcError::Remote { .. }))),
+            }
+        }
+    }
+}
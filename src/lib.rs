@@ -0,0 +1,243 @@
+use std::fmt;
+
+use serde::de::{self, value::MapDeserializer, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+pub mod broadcast;
+pub mod config;
+pub mod counter;
+pub mod crdt;
+pub mod encoding;
+pub mod error;
+pub mod hash;
+pub mod iblt;
+pub mod kafka;
+pub mod kv;
+pub mod logging;
+pub mod merkle;
+pub mod pn_counter;
+pub mod replication;
+pub mod rpc;
+pub mod runtime;
+pub mod sim;
+pub mod timer;
+pub mod topology;
+pub mod transport;
+pub mod txn;
+pub mod writer;
+
+pub use error::Error;
+use error::ErrorCode;
+
+/// Messages every node understands, whatever workload it serves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Protocol {
+    // Maelstrom init payloads
+    Init {
+        node_id: String,
+        node_ids: Vec<String>,
+    },
+    InitOk,
+
+    // Maelstrom error replies
+    Error {
+        code: ErrorCode,
+        #[serde(default)]
+        text: String,
+    },
+}
+
+/// An incoming payload: one of the shared protocol messages, one of the
+/// workload's own payloads `P`, or a message type neither knows about, kept
+/// as the raw JSON body. A type that is known but whose fields don't match
+/// fails to deserialize rather than ending up as `Unknown`.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Body<P> {
+    Protocol(Protocol),
+    Workload(P),
+    Unknown(Value),
+}
+
+impl<'de, P: DeserializeOwned> Deserialize<'de> for Body<P> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        let tag = value["type"].as_str().unwrap_or_default();
+        let body = if defines::<Protocol>(tag) {
+            serde_json::from_value(value).map(Body::Protocol)
+        } else if defines::<P>(tag) {
+            serde_json::from_value(value).map(Body::Workload)
+        } else {
+            return Ok(Body::Unknown(value));
+        };
+        body.map_err(de::Error::custom)
+    }
+}
+
+/// Whether the `type`-tagged enum `T` has a variant called `tag`, found by
+/// deserializing a body that has nothing but the tag.
+fn defines<T: DeserializeOwned>(tag: &str) -> bool {
+    let body = MapDeserializer::<_, TagError>::new(std::iter::once(("type", tag)));
+    !matches!(T::deserialize(body), Err(TagError::UnknownVariant))
+}
+
+/// Tells an unknown tag apart from the missing fields of a known one.
+#[derive(Debug)]
+enum TagError {
+    UnknownVariant,
+    Other,
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for TagError {}
+
+impl de::Error for TagError {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        TagError::Other
+    }
+
+    fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+        TagError::UnknownVariant
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct MessageBody<P> {
+    pub msg_id: Option<usize>,
+    pub in_reply_to: Option<usize>,
+    #[serde(rename = "type")]
+    #[serde(flatten)]
+    pub message: P,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub struct Message<P> {
+    pub src: String,
+    pub dest: String,
+    pub body: MessageBody<P>,
+}
+
+impl<P> Message<P> {
+    /// Swaps the payload for `message`, keeping the envelope.
+    pub fn with_payload<Q>(self, message: Q) -> Message<Q> {
+        Message {
+            src: self.src,
+            dest: self.dest,
+            body: MessageBody {
+                msg_id: self.body.msg_id,
+                in_reply_to: self.body.in_reply_to,
+                message,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    #[test]
+    #[allow(clippy::identity_op)]
+    fn test_echo_serde() {
+        use serde_json::*;
+        let message = Message {
+            src: "n1".to_string(),
+            dest: "c1".to_string(),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: Some(usize::MIN + 1),
+                message: Payload::EchoOk {
+                    echo: "Please echo 35".to_string(),
+                },
+            },
+        };
+        let expected: Message<Payload> = from_value(json! ({
+          "src": "n1",
+          "dest": "c1",
+          "body": {
+            "type": "echo_ok",
+            "msg_id": 1,
+            "in_reply_to": 1,
+            "echo": "Please echo 35"
+          }
+        }))
+        .expect("Failed to parse the expected message");
+        assert_eq!(message, expected, "Failed to to match the serialied values");
+        assert_eq!(
+            to_string(&message).expect("Could not deserialize the message"),
+            to_string(&expected).expect("Could not deserialize the expected message"),
+            "Failed to match the deserialied values"
+        );
+    }
+
+    #[test]
+    fn test_error_serde() {
+        use serde_json::*;
+        let message: Message<Body<Payload>> = from_value(json!({
+          "src": "n1",
+          "dest": "c1",
+          "body": {
+            "type": "error",
+            "in_reply_to": 5,
+            "code": 22,
+            "text": "expected 3, had 4"
+          }
+        }))
+        .expect("Failed to parse the error message");
+        assert_eq!(
+            message.body.message,
+            Body::Protocol(Protocol::Error {
+                code: ErrorCode::PreconditionFailed,
+                text: "expected 3, had 4".to_string()
+            })
+        );
+        assert!(ErrorCode::PreconditionFailed.is_definite());
+        assert!(!ErrorCode::from(0).is_definite());
+        assert_eq!(ErrorCode::from(1005), ErrorCode::Custom(1005));
+
+        let echo: Message<Body<Payload>> = from_str(
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 2, "echo": "hi"}}"#,
+        )
+        .expect("Failed to parse the echo message");
+        assert_eq!(
+            echo.body.message,
+            Body::Workload(Payload::Echo {
+                echo: "hi".to_string()
+            })
+        );
+
+        let unknown: Message<Body<Payload>> = from_str(
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "frobnicate", "msg_id": 3, "n": 1}}"#,
+        )
+        .expect("Unknown message types should still parse");
+        assert_eq!(
+            unknown.body.message,
+            Body::Unknown(json!({"type": "frobnicate", "n": 1}))
+        );
+        assert_eq!(unknown.body.msg_id, Some(3));
+
+        let malformed = from_str::<Message<Body<Payload>>>(
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 4, "echo": 5}}"#,
+        )
+        .expect_err("Known types with the wrong fields should not parse");
+        assert!(malformed.to_string().contains("invalid type"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::str::FromStr;
+
+use tracing_subscriber::EnvFilter;
+
+/// How log lines are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, with the spans it happened in.
+    Json,
+}
+
+impl LogFormat {
+    /// Reads the format from `FESTROM_LOG_FORMAT`, falling back to text.
+    pub fn from_env() -> Self {
+        std::env::var("FESTROM_LOG_FORMAT")
+            .ok()
+            .and_then(|format| format.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("Unknown log format: {}", other)),
+        }
+    }
+}
+
+/// Logs to stderr, where Maelstrom collects each node's logs. Verbosity comes
+/// from `RUST_LOG` and defaults to `info`; every message in and out is logged
+/// at `debug`. Does nothing if logging is already set up.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(false);
+    let _ = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_span_list(true).try_init(),
+    };
+}
@@ -0,0 +1,207 @@
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash::mix;
+
+/// A node of a `MerkleTree`. The root is on level 0, and the `index`th node
+/// of a level covers the `index`th of its `2^level` equal ranges of keys.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Node {
+    pub level: u32,
+    pub index: u64,
+}
+
+impl Node {
+    pub const ROOT: Node = Node { level: 0, index: 0 };
+
+    /// Whether `key` falls in the range this node covers. Nodes deeper than
+    /// a key has bits cover nothing.
+    pub fn contains(&self, key: u64) -> bool {
+        match 64u32.checked_sub(self.level) {
+            Some(64) => true,
+            Some(shift) => key >> shift == self.index,
+            None => false,
+        }
+    }
+
+    fn children(&self) -> [Node; 2] {
+        let level = self.level + 1;
+        [
+            Node {
+                level,
+                index: self.index * 2,
+            },
+            Node {
+                level,
+                index: self.index * 2 + 1,
+            },
+        ]
+    }
+}
+
+/// A hash tree over the range of 64-bit keys, so that two replicas can find
+/// where their states differ in as many round trips as the tree is deep,
+/// exchanging hashes only for the ranges that differ. Each entry is a key,
+/// which picks its leaf, and a hash of its contents. A set only needs keys,
+/// while a key/value store would hash each value with its version, and
+/// replace an entry by removing the old one and inserting the new one.
+///
+/// Replicas can only be compared if their trees are equally deep.
+pub struct MerkleTree {
+    depth: u32,
+    /// The hashes of every node, level by level from the root.
+    levels: Vec<Vec<u64>>,
+}
+
+/// The outcome of comparing a peer's hashes with ours.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Comparison {
+    /// The children of inner nodes that differ, with our hashes, for the peer
+    /// to compare in turn.
+    pub next: Vec<(Node, u64)>,
+    /// Leaves that differ, whose entries need repairing.
+    pub leaves: Vec<Node>,
+}
+
+impl MerkleTree {
+    pub fn new(depth: u32) -> Self {
+        assert!(depth < 64, "A Merkle tree can be at most 63 levels deep");
+        MerkleTree {
+            depth,
+            levels: (0..=depth).map(|level| vec![0; 1 << level]).collect(),
+        }
+    }
+
+    /// Whether `node` is part of this tree, so that it's safe to look up.
+    pub fn has(&self, node: Node) -> bool {
+        node.level <= self.depth && node.index < 1 << node.level
+    }
+
+    /// The leaf `key` falls in.
+    pub fn leaf(&self, key: u64) -> Node {
+        let index = if self.depth == 0 {
+            0
+        } else {
+            key >> (64 - self.depth)
+        };
+        Node {
+            level: self.depth,
+            index,
+        }
+    }
+
+    /// The indices of the leaves below `node`, which must be in the tree.
+    pub fn leaves(&self, node: Node) -> Range<u64> {
+        let shift = self.depth - node.level;
+        node.index << shift..(node.index + 1) << shift
+    }
+
+    pub fn hash(&self, node: Node) -> u64 {
+        self.levels[node.level as usize][node.index as usize]
+    }
+
+    pub fn root(&self) -> (Node, u64) {
+        (Node::ROOT, self.hash(Node::ROOT))
+    }
+
+    pub fn insert(&mut self, key: u64, hash: u64) {
+        self.toggle(key, hash);
+    }
+
+    /// Takes out an entry that was inserted before.
+    pub fn remove(&mut self, key: u64, hash: u64) {
+        self.toggle(key, hash);
+    }
+
+    /// Checks `theirs`, nodes and the peer's hashes for them, against ours.
+    /// Nodes that aren't in our tree are skipped.
+    pub fn compare(&self, theirs: &[(Node, u64)]) -> Comparison {
+        let mut comparison = Comparison::default();
+        for &(node, hash) in theirs {
+            if !self.has(node) || self.hash(node) == hash {
+                continue;
+            }
+            if node.level == self.depth {
+                comparison.leaves.push(node);
+            } else {
+                for child in node.children() {
+                    comparison.next.push((child, self.hash(child)));
+                }
+            }
+        }
+        comparison
+    }
+
+    /// Leaf hashes combine their entries with XOR, which doesn't care about
+    /// order and undoes itself on removal. Every node above is rehashed.
+    fn toggle(&mut self, key: u64, hash: u64) {
+        let mut index = self.leaf(key).index as usize;
+        self.levels[self.depth as usize][index] ^= mix(hash);
+        for level in (0..self.depth as usize).rev() {
+            index /= 2;
+            let children = &self.levels[level + 1];
+            let (left, right) = (children[index * 2], children[index * 2 + 1]);
+            self.levels[level][index] = mix(left ^ mix(right));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comparison_narrows_down_to_differing_leaves() {
+        let (mut ours, mut theirs) = (MerkleTree::new(8), MerkleTree::new(8));
+        for value in 0..1000u64 {
+            ours.insert(mix(value), value);
+            theirs.insert(mix(value), value);
+        }
+        assert_eq!(ours.root(), theirs.root());
+
+        ours.insert(mix(5000), 5000);
+        theirs.remove(mix(7), 7);
+        // Replicas take turns comparing what the other sent
+        let mut sent = vec![ours.root()];
+        let mut leaves = Vec::new();
+        let mut rounds = 0;
+        while !sent.is_empty() {
+            let comparison = if rounds % 2 == 0 {
+                theirs.compare(&sent)
+            } else {
+                ours.compare(&sent)
+            };
+            leaves.extend(comparison.leaves);
+            sent = comparison.next;
+            rounds += 1;
+        }
+        assert_eq!(rounds, 9);
+        assert_eq!(leaves.len(), 2);
+        for key in [mix(5000), mix(7)] {
+            assert!(leaves.iter().any(|leaf| leaf.contains(key)));
+        }
+
+        theirs.insert(mix(5000), 5000);
+        theirs.insert(mix(7), 7);
+        assert_eq!(ours.root(), theirs.root());
+
+        assert_eq!(ours.leaves(Node { level: 6, index: 3 }), 12..16);
+        assert!(!ours.has(Node { level: 9, index: 0 }));
+        assert!(!ours.has(Node {
+            level: 8,
+            index: 256
+        }));
+        assert!(Node::ROOT.contains(u64::MAX));
+        assert!(Node {
+            level: 64,
+            index: 7
+        }
+        .contains(7));
+        assert!(!Node {
+            level: 65,
+            index: 0
+        }
+        .contains(0));
+    }
+}
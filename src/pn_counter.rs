@@ -0,0 +1,115 @@
+use rand::prelude::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::config::GossipConfig;
+use crate::crdt::{PnCounter, Replica};
+use crate::runtime::{Context, Handler};
+use crate::Message;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Add { delta: i64 },
+    AddOk,
+    Read,
+    ReadOk { value: i64 },
+
+    // Used to gossip the counts peers haven't acknowledged
+    Replicate { seq: u64, counter: PnCounter },
+    ReplicateOk { seq: u64 },
+}
+
+/// The pn-counter workload. Every node counts its own increments and
+/// decrements, and gossips the counts to its peers, so adds and reads keep
+/// working through partitions without seq-kv.
+#[derive(Default)]
+pub struct PnCounterNode {
+    replica: Replica<PnCounter>,
+    config: GossipConfig,
+}
+
+impl PnCounterNode {
+    pub fn new(config: GossipConfig) -> Self {
+        PnCounterNode {
+            config,
+            ..Default::default()
+        }
+    }
+}
+
+impl Handler for PnCounterNode {
+    type Payload = Payload;
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        match &msg.body.message {
+            Payload::Add { delta } => {
+                self.replica.state_mut().add(ctx.node_id(), *delta);
+                ctx.reply(&msg, Payload::AddOk);
+            }
+            Payload::Read => {
+                let value = self.replica.state().value();
+                ctx.reply(&msg, Payload::ReadOk { value });
+            }
+            Payload::Replicate { seq, counter } => {
+                self.replica.incoming(&msg.src, counter);
+                ctx.send(&msg.src, Payload::ReplicateOk { seq: *seq });
+            }
+            Payload::ReplicateOk { seq } => self.replica.acknowledged(&msg.src, *seq),
+            _ => ctx.not_supported(&msg),
+        }
+    }
+
+    fn on_tick(&mut self, ctx: &Context) {
+        let peers: Vec<&String> = match self.config.fan_out {
+            Some(fan_out) => ctx.peers().choose_multiple(&mut *ctx.rng(), fan_out),
+            None => ctx.peers().collect(),
+        };
+        for peer in peers {
+            if let Some((seq, counter)) = self.replica.outgoing(peer) {
+                ctx.send(peer, Payload::Replicate { seq, counter });
+            }
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.config.period)
+    }
+
+    fn tick_jitter(&self) -> Duration {
+        self.config.jitter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sim::{Network, Simulation};
+
+    #[test]
+    fn test_pn_counter_stays_available_through_partitions() {
+        let network = Network {
+            drop_rate: 0.2,
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(11, network, 3, PnCounterNode::default);
+        let timeout = Duration::from_secs(1);
+        sim.partition(&[&["n0"], &["n1", "n2"]]);
+        for (node, delta) in [("n0", 5), ("n1", -8), ("n2", 2), ("n0", -1)] {
+            let reply: Option<Payload> = sim.call(node, Payload::Add { delta }, timeout);
+            assert_eq!(reply, Some(Payload::AddOk));
+        }
+        sim.run_for(Duration::from_secs(2));
+        let read = |sim: &mut Simulation<PnCounterNode>, node| {
+            sim.call::<_, Payload>(node, Payload::Read, timeout)
+        };
+        assert_eq!(read(&mut sim, "n0"), Some(Payload::ReadOk { value: 4 }));
+        assert_eq!(read(&mut sim, "n2"), Some(Payload::ReadOk { value: -6 }));
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(5));
+        sim.assert_converged(Payload::Read, Payload::ReadOk { value: -2 });
+    }
+}
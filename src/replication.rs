@@ -0,0 +1,178 @@
+use std::collections::{HashMap, HashSet};
+
+/// An append-only log replicated to peers in sequence-numbered deltas. An
+/// entry's sequence number is its position in the log, counting from 1. Each
+/// side acknowledges how much of the other's log it has without gaps, and
+/// whatever lies past a peer's watermark keeps being resent until it
+/// acknowledges it.
+#[derive(Debug)]
+pub struct ReplicatedLog<T> {
+    entries: Vec<T>,
+    /// How much of our log each peer has acknowledged.
+    acknowledged: HashMap<String, usize>,
+    /// How much of each peer's log we have received without gaps.
+    received: HashMap<String, usize>,
+    /// Peers we have received from since we last acknowledged them.
+    owed_acks: HashSet<String>,
+    /// How far into our log we last sent each peer, and how much of it the
+    /// peer had acknowledged at the time.
+    sent: HashMap<String, (usize, usize)>,
+}
+
+impl<T> Default for ReplicatedLog<T> {
+    fn default() -> Self {
+        ReplicatedLog {
+            entries: Vec::new(),
+            acknowledged: HashMap::new(),
+            received: HashMap::new(),
+            owed_acks: HashSet::new(),
+            sent: HashMap::new(),
+        }
+    }
+}
+
+impl<T> ReplicatedLog<T> {
+    pub fn push(&mut self, entry: T) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[T] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How much of our log `peer` has acknowledged.
+    pub fn acknowledged(&self, peer: &str) -> usize {
+        self.acknowledged.get(peer).copied().unwrap_or_default()
+    }
+
+    /// Records how much of our log `peer` has. Acks can arrive out of order,
+    /// so the watermark never moves back.
+    pub fn acknowledge(&mut self, peer: &str, ack: usize) {
+        let watermark = self.acknowledged.entry(peer.to_string()).or_default();
+        *watermark = (*watermark).max(ack.min(self.entries.len()));
+    }
+
+    /// Records `count` entries of `peer`'s log following its first `from`.
+    /// Its watermark only moves when they continue what we already had, so a
+    /// lost delta gets sent again.
+    pub fn receive(&mut self, peer: &str, from: usize, count: usize) {
+        let watermark = self.received.entry(peer.to_string()).or_default();
+        if from <= *watermark {
+            *watermark = (*watermark).max(from + count);
+        }
+        if count > 0 {
+            self.owed_acks.insert(peer.to_string());
+        }
+    }
+
+    /// How much of `peer`'s log we have without gaps, which is our ack.
+    pub fn received(&self, peer: &str) -> usize {
+        self.received.get(peer).copied().unwrap_or_default()
+    }
+
+    /// What to send `peer` next, as `(from, entries, ack)`: the entries we
+    /// haven't sent it yet, at most `max_payload` of them, along with
+    /// `redundancy` as many of the ones still waiting for its ack. Once a
+    /// delta goes unacknowledged until the next one, everything since the
+    /// peer's watermark is taken to be lost and sent again.
+    pub fn delta(
+        &self,
+        peer: &str,
+        max_payload: Option<usize>,
+        redundancy: f64,
+    ) -> (usize, &[T], usize) {
+        let acknowledged = self.acknowledged(peer);
+        let sent = match self.sent.get(peer) {
+            Some(&(sent, acknowledged_then)) if acknowledged_then < acknowledged => {
+                sent.max(acknowledged)
+            }
+            _ => acknowledged,
+        };
+        let max_payload = max_payload.unwrap_or(usize::MAX);
+        let end = sent.saturating_add(max_payload).min(self.entries.len());
+        let new = end - sent;
+        // The latest of them, so that the delta doesn't skip any entries
+        let resent = ((new as f64 * redundancy) as usize)
+            .min(sent - acknowledged)
+            .min(max_payload - new);
+        let from = sent - resent;
+        (from, &self.entries[from..end], self.received(peer))
+    }
+
+    /// Whether `peer` is missing part of our log or is owed an ack.
+    pub fn has_news_for(&self, peer: &str) -> bool {
+        self.acknowledged(peer) < self.entries.len() || self.owed_acks.contains(peer)
+    }
+
+    /// Records that a delta with our log up to `end` went to `peer`. Returns
+    /// whether the ack it carries was owed.
+    pub fn sent(&mut self, peer: &str, end: usize) -> bool {
+        let acknowledged = self.acknowledged(peer);
+        self.sent.insert(peer.to_string(), (end, acknowledged));
+        self.ack_sent(peer)
+    }
+
+    /// Records that our ack went to `peer` without any entries. Returns
+    /// whether one was owed.
+    pub fn ack_sent(&mut self, peer: &str) -> bool {
+        self.owed_acks.remove(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watermarks_only_move_without_gaps() {
+        let mut log = ReplicatedLog::default();
+        for entry in ['a', 'b', 'c'] {
+            log.push(entry);
+        }
+        assert_eq!(log.delta("n2", Some(2), 0.0), (0, &['a', 'b'][..], 0));
+        log.acknowledge("n2", 2);
+        log.acknowledge("n2", 1);
+        assert_eq!(log.delta("n2", None, 0.0), (2, &['c'][..], 0));
+        log.acknowledge("n2", 3);
+        assert!(!log.has_news_for("n2"));
+
+        // A delta past a gap is kept back from the ack
+        log.receive("n2", 2, 1);
+        assert_eq!(log.received("n2"), 0);
+        log.receive("n2", 0, 2);
+        assert_eq!(log.received("n2"), 2);
+        assert!(log.has_news_for("n2"));
+        assert!(log.sent("n2", 3));
+        assert!(!log.has_news_for("n2"));
+    }
+
+    #[test]
+    fn test_only_unacknowledged_entries_are_resent() {
+        let mut log = ReplicatedLog::default();
+        for entry in 0..4 {
+            log.push(entry);
+        }
+        assert_eq!(log.delta("n2", None, 1.0), (0, &[0, 1, 2, 3][..], 0));
+        log.sent("n2", 4);
+        // n2 got the first two, and 4 and 5 are new
+        log.acknowledge("n2", 2);
+        log.push(4);
+        log.push(5);
+        assert_eq!(log.delta("n2", None, 0.0), (4, &[4, 5][..], 0));
+        assert_eq!(log.delta("n2", None, 0.5), (3, &[3, 4, 5][..], 0));
+        assert_eq!(log.delta("n2", None, 1.0), (2, &[2, 3, 4, 5][..], 0));
+        assert_eq!(log.delta("n2", Some(3), 1.0), (3, &[3, 4, 5][..], 0));
+
+        // Without an ack since, the whole delta looks lost
+        log.sent("n2", 6);
+        assert_eq!(log.delta("n2", Some(3), 0.0), (2, &[2, 3, 4][..], 0));
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{self, Duration};
+
+use crate::error::{ErrorCode, MaelstromError};
+use crate::{Message, MessageBody, Protocol};
+
+/// How long `Rpc::rpc` waits for a reply before giving up on it.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    #[error("RPC {msg_id} to {dest} timed out")]
+    Timeout { dest: String, msg_id: usize },
+    #[error("RPC {msg_id} to {dest} was dropped before a reply arrived")]
+    Closed { dest: String, msg_id: usize },
+    #[error("{dest} replied with {code} error: {text}")]
+    Remote {
+        dest: String,
+        code: ErrorCode,
+        text: String,
+    },
+    #[error("{dest} sent an unexpected reply: {reply}")]
+    Unexpected { dest: String, reply: Value },
+}
+
+impl MaelstromError for RpcError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            RpcError::Timeout { .. } => ErrorCode::Timeout,
+            RpcError::Remote { code, .. } => *code,
+            RpcError::Closed { .. } | RpcError::Unexpected { .. } => ErrorCode::Crash,
+        }
+    }
+}
+
+/// A message queued for sending. The node fills in `src` when it writes it out,
+/// so that the handle can be used before `init` has told us who we are.
+#[derive(Debug)]
+pub struct Outbound {
+    pub dest: String,
+    pub body: MessageBody<Value>,
+}
+
+/// Cloneable handle for sending messages and awaiting replies from anywhere,
+/// including tasks spawned off the main event loop. Payloads of any type can
+/// go through it; they travel as JSON until whoever awaits a reply decides
+/// what type it should have.
+#[derive(Clone)]
+pub struct Rpc {
+    next_msg_id: Arc<AtomicUsize>,
+    pending: Arc<Mutex<HashMap<usize, oneshot::Sender<Value>>>>,
+    out: mpsc::UnboundedSender<Outbound>,
+}
+
+impl Rpc {
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<Outbound>) {
+        let (out, rx) = mpsc::unbounded_channel();
+        let rpc = Rpc {
+            next_msg_id: Arc::new(AtomicUsize::new(1)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            out,
+        };
+        (rpc, rx)
+    }
+
+    /// Allocates the next msg_id for this node. Ids are never reused.
+    pub fn next_msg_id(&self) -> usize {
+        self.next_msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Queues a message without expecting any reply.
+    pub fn send<P: Serialize>(&self, dest: String, body: MessageBody<P>) {
+        let body = MessageBody {
+            msg_id: body.msg_id,
+            in_reply_to: body.in_reply_to,
+            message: serde_json::to_value(body.message)
+                .expect("Failed to convert payload (to be sent) to JSON."),
+        };
+        // The receiver only goes away when the event loop shuts down
+        let _ = self.out.send(Outbound { dest, body });
+    }
+
+    /// Queues a reply to `request`, reporting `result`'s error if it failed.
+    pub fn reply<Q, P: Serialize, E: MaelstromError>(
+        &self,
+        request: &Message<Q>,
+        result: Result<P, E>,
+    ) {
+        let payload = match result {
+            Ok(payload) => serde_json::to_value(payload),
+            Err(err) => serde_json::to_value(Protocol::Error {
+                code: err.code(),
+                text: err.to_string(),
+            }),
+        };
+        self.send(
+            request.src.clone(),
+            MessageBody {
+                msg_id: Some(self.next_msg_id()),
+                in_reply_to: request.body.msg_id,
+                message: payload.expect("Failed to convert reply to JSON."),
+            },
+        );
+    }
+
+    /// Sends `payload` to `dest` and resolves with the payload of its reply.
+    pub fn rpc<P: Serialize, R: DeserializeOwned>(
+        &self,
+        dest: String,
+        payload: P,
+    ) -> impl Future<Output = Result<R, RpcError>> + Send + 'static {
+        self.rpc_with_timeout(dest, payload, DEFAULT_TIMEOUT)
+    }
+
+    pub fn rpc_with_timeout<P: Serialize, R: DeserializeOwned>(
+        &self,
+        dest: String,
+        payload: P,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<R, RpcError>> + Send + 'static {
+        let msg_id = self.next_msg_id();
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .expect("RPC table lock poisoned")
+            .insert(msg_id, tx);
+        self.send(
+            dest.clone(),
+            MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                message: payload,
+            },
+        );
+
+        let pending = PendingEntry {
+            pending: self.pending.clone(),
+            msg_id,
+        };
+        async move {
+            // However this future ends, even by being dropped, the entry goes
+            let _pending = pending;
+            let reply = match time::timeout(timeout, rx).await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(_)) => return Err(RpcError::Closed { dest, msg_id }),
+                Err(_) => return Err(RpcError::Timeout { dest, msg_id }),
+            };
+            if let Ok(Protocol::Error { code, text }) = serde_json::from_value(reply.clone()) {
+                return Err(RpcError::Remote { dest, code, text });
+            }
+            serde_json::from_value(reply.clone()).map_err(|_| RpcError::Unexpected { dest, reply })
+        }
+    }
+
+    /// Hands `msg` to whoever is waiting on it. Returns the message back if it
+    /// isn't a reply to one of our outstanding RPCs.
+    pub fn resolve(&self, msg: Message<Value>) -> Option<Message<Value>> {
+        let waiting = msg.body.in_reply_to.and_then(|id| {
+            self.pending
+                .lock()
+                .expect("RPC table lock poisoned")
+                .remove(&id)
+        });
+        match waiting {
+            Some(tx) => {
+                // The caller may have given up already; a late reply is just dropped
+                let _ = tx.send(msg.body.message);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+}
+
+/// Removes an RPC's entry from the pending table when dropped.
+struct PendingEntry {
+    pending: Arc<Mutex<HashMap<usize, oneshot::Sender<Value>>>>,
+    msg_id: usize,
+}
+
+impl Drop for PendingEntry {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .expect("RPC table lock poisoned")
+            .remove(&self.msg_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_rpc_reply_correlation() {
+        let (rpc, mut out) = Rpc::new();
+        let call = tokio::spawn(
+            rpc.rpc::<_, Value>("n2".to_string(), json!({"type": "echo", "echo": "ping"})),
+        );
+
+        let sent = out.recv().await.expect("RPC was not queued for sending");
+        assert_eq!(sent.dest, "n2");
+        let request_id = sent.body.msg_id.expect("RPC went out without a msg_id");
+
+        let reply = Message {
+            src: "n2".to_string(),
+            dest: "n1".to_string(),
+            body: MessageBody {
+                msg_id: Some(1),
+                in_reply_to: Some(request_id),
+                message: json!({"type": "echo_ok", "echo": "ping"}),
+            },
+        };
+        assert!(rpc.resolve(reply).is_none(), "Reply was not claimed");
+        assert_eq!(
+            call.await.expect("RPC task panicked").expect("RPC failed"),
+            json!({"type": "echo_ok", "echo": "ping"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpc_timeout_clears_pending() {
+        let (rpc, _out) = Rpc::new();
+        let result = rpc
+            .rpc_with_timeout::<_, Value>(
+                "n2".to_string(),
+                json!({"type": "read"}),
+                Duration::from_millis(10),
+            )
+            .await;
+        assert!(matches!(result, Err(RpcError::Timeout { .. })));
+        assert!(rpc.pending.lock().unwrap().is_empty());
+
+        // A caller that gives up early doesn't leave its entry behind
+        let abandoned = rpc.rpc::<_, Value>("n2".to_string(), json!({"type": "read"}));
+        assert_eq!(rpc.pending.lock().unwrap().len(), 1);
+        drop(abandoned);
+        assert!(rpc.pending.lock().unwrap().is_empty());
+    }
+}
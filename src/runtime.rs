@@ -0,0 +1,448 @@
+use std::cell::{RefCell, RefMut};
+use std::pin::Pin;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+
+use tracing::{debug, field, info_span, warn, Instrument, Span};
+
+use crate::error::{Error, ErrorCode};
+use crate::logging::{self, LogFormat};
+use crate::rpc::{Outbound, Rpc};
+use crate::transport::{Cluster, MessageSink, Stdio, Transport};
+use crate::writer::DEFAULT_CAPACITY;
+use crate::{Body, Message, MessageBody, Protocol};
+
+/// Tick period for workloads that gossip state between nodes.
+pub const DEFAULT_TICK: Duration = Duration::from_millis(300);
+
+/// What a handler knows about the node it runs on, and how it talks to others.
+pub struct Context {
+    node_id: String,
+    node_ids: Vec<String>,
+    rpc: Rpc,
+    rng: RefCell<StdRng>,
+}
+
+impl Context {
+    pub fn new(rpc: Rpc) -> Self {
+        Context::with_rng(rpc, StdRng::from_entropy())
+    }
+
+    /// A context whose rng is seeded with `seed`, so that whatever a handler
+    /// picks at random is picked the same way on every run.
+    pub fn seeded(rpc: Rpc, seed: u64) -> Self {
+        Context::with_rng(rpc, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(rpc: Rpc, rng: StdRng) -> Self {
+        Context {
+            node_id: String::new(),
+            node_ids: Vec::new(),
+            rpc,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Every node in the cluster except this one.
+    pub fn peers(&self) -> impl Iterator<Item = &String> {
+        self.node_ids.iter().filter(|node| **node != self.node_id)
+    }
+
+    /// The rng handlers should make their random choices with, e.g. of
+    /// peers or jitter.
+    pub fn rng(&self) -> RefMut<'_, StdRng> {
+        self.rng.borrow_mut()
+    }
+
+    /// The handle to send messages or await replies with, which can be cloned
+    /// into spawned tasks.
+    pub fn rpc(&self) -> &Rpc {
+        &self.rpc
+    }
+
+    /// Sends a message that doesn't expect a reply.
+    pub fn send<P: Serialize>(&self, dest: &str, payload: P) {
+        self.rpc.send(
+            dest.to_string(),
+            MessageBody {
+                msg_id: None,
+                in_reply_to: None,
+                message: payload,
+            },
+        );
+    }
+
+    pub fn reply<Q, P: Serialize>(&self, request: &Message<Q>, payload: P) {
+        self.rpc.send(
+            request.src.clone(),
+            MessageBody {
+                msg_id: Some(self.rpc.next_msg_id()),
+                in_reply_to: request.body.msg_id,
+                message: payload,
+            },
+        );
+    }
+
+    /// Tells the sender we don't handle this kind of message. Replies and
+    /// messages without a msg_id are dropped instead, since nobody is
+    /// waiting on an answer to those.
+    pub fn not_supported<Q>(&self, request: &Message<Q>) {
+        if request.body.msg_id.is_some() && request.body.in_reply_to.is_none() {
+            self.reply(
+                request,
+                Protocol::Error {
+                    code: ErrorCode::NotSupported,
+                    text: "message type not supported".to_string(),
+                },
+            );
+        }
+    }
+}
+
+/// A workload served by a node. The runtime answers `init`, routes RPC
+/// replies and rejects message types the workload doesn't define; everything
+/// else goes to the handler as its own `Payload` type.
+pub trait Handler {
+    type Payload: DeserializeOwned;
+
+    /// Called once the node knows its id and the rest of the cluster.
+    fn init(&mut self, _ctx: &Context) {}
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Self::Payload>);
+
+    /// Called every `tick_interval`, if the handler asks for ticks at all.
+    fn on_tick(&mut self, _ctx: &Context) {}
+
+    fn tick_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Up to this much is randomly added to every `tick_interval`.
+    fn tick_jitter(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+enum Event {
+    TransportMessage(Message<Value>),
+    Outbound(Outbound),
+    Tick,
+}
+
+/// Routes an incoming message on its way to `handler`: replies to our own RPCs
+/// go to whoever is awaiting them, `init` sets up `ctx`, and anything the
+/// workload doesn't define, or defines with other fields, is rejected.
+pub(crate) fn dispatch<H: Handler>(handler: &mut H, ctx: &mut Context, msg: Message<Value>) {
+    let Some(msg) = ctx.rpc.resolve(msg) else {
+        return;
+    };
+    let body = serde_json::from_value::<Body<H::Payload>>(msg.body.message.clone());
+    match body {
+        Ok(Body::Protocol(Protocol::Init { node_id, node_ids })) => {
+            ctx.node_id = node_id;
+            ctx.node_ids = node_ids;
+            handler.init(ctx);
+            ctx.reply(&msg, Protocol::InitOk);
+        }
+        Ok(Body::Protocol(Protocol::Error { code, text })) => {
+            warn!(%code, src = %msg.src, text, "Got an unsolicited error");
+        }
+        Ok(Body::Protocol(Protocol::InitOk)) => {}
+        Ok(Body::Workload(payload)) => handler.on_message(ctx, msg.with_payload(payload)),
+        Ok(Body::Unknown(_)) => ctx.not_supported(&msg),
+        Err(err) => {
+            warn!(%err, "Rejecting a malformed request");
+            if msg.body.msg_id.is_some() && msg.body.in_reply_to.is_none() {
+                ctx.reply(
+                    &msg,
+                    Protocol::Error {
+                        code: ErrorCode::MalformedRequest,
+                        text: err.to_string(),
+                    },
+                );
+            }
+        }
+    }
+}
+
+/// Answers a message we couldn't parse with a `malformed-request` error, if
+/// enough of it survives to tell who sent it and which request it was.
+fn reject_malformed(ctx: &Context, line: &str, source: &serde_json::Error) {
+    let Ok(value) = serde_json::from_str::<Value>(line) else {
+        return;
+    };
+    let (Some(src), Some(msg_id)) = (value["src"].as_str(), value["body"]["msg_id"].as_u64())
+    else {
+        return;
+    };
+    ctx.rpc.send(
+        src.to_string(),
+        MessageBody {
+            msg_id: Some(ctx.rpc.next_msg_id()),
+            in_reply_to: Some(msg_id as usize),
+            message: Protocol::Error {
+                code: ErrorCode::MalformedRequest,
+                text: source.to_string(),
+            },
+        },
+    );
+}
+
+/// Serves `handler` over the Maelstrom stdin/stdout protocol until stdin
+/// closes, or over sockets when `Cluster::from_env` finds a cluster to join.
+/// Random choices are seeded from `FESTROM_SEED` when it is set, so that a
+/// run can be repeated.
+pub async fn run<H: Handler>(handler: H) -> Result<(), Error> {
+    logging::init(LogFormat::from_env());
+    let rng = seed_from_env().map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+    match Cluster::from_env()? {
+        Some(cluster) => serve_with_rng(handler, cluster.bind().await?, rng).await,
+        None => serve_with_rng(handler, Stdio::new(DEFAULT_CAPACITY), rng).await,
+    }
+}
+
+fn seed_from_env() -> Option<u64> {
+    std::env::var("FESTROM_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+}
+
+/// Serves `handler` over `transport` until it runs out of messages or fails.
+/// Whatever the handler sent in answer to the last of them still goes out.
+pub async fn serve<H: Handler, T: Transport>(handler: H, transport: T) -> Result<(), Error> {
+    serve_with_rng(handler, transport, StdRng::from_entropy()).await
+}
+
+/// Like `serve`, but with every random choice the node makes, the handler's
+/// as well as the tick jitter, drawn from `rng`.
+pub async fn serve_with_rng<H: Handler, T: Transport>(
+    handler: H,
+    transport: T,
+    mut rng: StdRng,
+) -> Result<(), Error> {
+    let (incoming, sink) = transport.split();
+    let (rpc, outbound) = Rpc::new();
+    let ctx = Context::seeded(rpc, rng.gen());
+
+    let ticks: Pin<Box<dyn Stream<Item = ()> + Send>> = match handler.tick_interval() {
+        Some(period) => Box::pin(ticks(tick_delays(period, handler.tick_jitter(), rng))),
+        None => Box::pin(tokio_stream::pending()),
+    };
+
+    let node = info_span!("node", node_id = field::Empty);
+    handle_events(handler, ctx, incoming, ticks, outbound, sink, &node)
+        .instrument(node.clone())
+        .await
+}
+
+async fn handle_events<H: Handler, S: MessageSink>(
+    mut handler: H,
+    mut ctx: Context,
+    mut incoming: impl Stream<Item = Result<Message<Value>, Error>> + Unpin,
+    mut ticks: impl Stream<Item = ()> + Unpin,
+    mut outbound: mpsc::UnboundedReceiver<Outbound>,
+    mut sink: S,
+    node: &Span,
+) -> Result<(), Error> {
+    loop {
+        let event = tokio::select! {
+            Some(msg) = outbound.recv() => Ok(Event::Outbound(msg)),
+            Some(()) = ticks.next() => Ok(Event::Tick),
+            msg = incoming.next() => match msg {
+                Some(msg) => msg.map(Event::TransportMessage),
+                None => break,
+            },
+        };
+        let event = match event {
+            Ok(event) => event,
+            Err(Error::MalformedMessage { line, source }) => {
+                warn!(%source, line, "Ignoring malformed message");
+                reject_malformed(&ctx, &line, &source);
+                continue;
+            }
+            // Lines that aren't UTF-8 are skipped over by the reader
+            Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::InvalidData => {
+                warn!(%err, "Ignoring unreadable input");
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        match event {
+            Event::TransportMessage(msg) => {
+                let _span = info_span!(
+                    "message",
+                    src = %msg.src,
+                    msg_id = msg.body.msg_id,
+                    "type" = payload_type(&msg.body),
+                )
+                .entered();
+                debug!(in_reply_to = msg.body.in_reply_to, body = %msg.body.message, "Received");
+                let initialised = !ctx.node_id.is_empty();
+                dispatch(&mut handler, &mut ctx, msg);
+                if !initialised && !ctx.node_id.is_empty() {
+                    node.record("node_id", ctx.node_id.as_str());
+                }
+            }
+            Event::Outbound(outbound) => send(&ctx.node_id, &mut sink, outbound).await?,
+            Event::Tick => handler.on_tick(&ctx),
+        }
+    }
+
+    // Input has ended, but replies to the last messages may still be queued
+    while let Ok(msg) = outbound.try_recv() {
+        send(&ctx.node_id, &mut sink, msg).await?;
+    }
+    sink.flush().await
+}
+
+async fn send<S: MessageSink>(
+    node_id: &str,
+    sink: &mut S,
+    Outbound { dest, body }: Outbound,
+) -> Result<(), Error> {
+    if node_id.is_empty() {
+        warn!(%dest, "type" = payload_type(&body), "Dropping a message queued before init");
+        return Ok(());
+    }
+    debug!(
+        %dest,
+        msg_id = body.msg_id,
+        in_reply_to = body.in_reply_to,
+        "type" = payload_type(&body),
+        body = %body.message,
+        "Sending"
+    );
+    sink.send(Message {
+        src: node_id.to_string(),
+        dest,
+        body,
+    })
+    .await
+}
+
+/// Every `period` plus up to `jitter`, picked with `rng`.
+fn tick_delays(
+    period: Duration,
+    jitter: Duration,
+    mut rng: StdRng,
+) -> impl Iterator<Item = Duration> + Send {
+    std::iter::repeat_with(move || period + jitter.mul_f64(rng.gen()))
+}
+
+/// Ticks after each of `delays`. A tick that isn't handled yet holds back the
+/// next one rather than letting them pile up.
+fn ticks(delays: impl Iterator<Item = Duration> + Send + 'static) -> impl Stream<Item = ()> {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        for delay in delays {
+            time::sleep(delay).await;
+            if tx.send(()).await.is_err() {
+                return;
+            }
+        }
+    });
+    ReceiverStream::new(rx)
+}
+
+fn payload_type(body: &MessageBody<Value>) -> &str {
+    body.message["type"].as_str().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::broadcast::BroadcastNode;
+    use crate::sim::{Network, Simulation};
+    use crate::transport::Channel;
+
+    use super::*;
+
+    #[test]
+    fn test_rejected_requests_get_the_right_error() {
+        let mut sim = Simulation::new(1, Network::default(), 1, BroadcastNode::default);
+        let timeout = Duration::from_secs(1);
+        let mut error_code = |body| match sim.call("n0", body, timeout) {
+            Some(Protocol::Error { code, .. }) => code,
+            reply => panic!("Expected an error, got {:?}", reply),
+        };
+        assert_eq!(
+            error_code(json!({"type": "broadcast", "message": "not a number"})),
+            ErrorCode::MalformedRequest
+        );
+        assert_eq!(
+            error_code(json!({"type": "init", "node_id": 1})),
+            ErrorCode::MalformedRequest
+        );
+        assert_eq!(
+            error_code(json!({"type": "frobnicate"})),
+            ErrorCode::NotSupported
+        );
+    }
+
+    #[tokio::test]
+    async fn test_serve_returns_once_input_ends() {
+        let (transport, to_node, mut from_node) = Channel::new();
+        let requests = [
+            json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]}),
+            json!({"type": "broadcast", "message": 7}),
+        ];
+        for (msg_id, message) in requests.into_iter().enumerate() {
+            let msg = Message {
+                src: "c1".to_string(),
+                dest: "n1".to_string(),
+                body: MessageBody {
+                    msg_id: Some(msg_id),
+                    in_reply_to: None,
+                    message,
+                },
+            };
+            to_node.send(msg).unwrap();
+        }
+        drop(to_node);
+
+        let served = time::timeout(
+            Duration::from_secs(1),
+            serve(BroadcastNode::default(), transport),
+        )
+        .await;
+        served
+            .expect("Node kept running after its input ended")
+            .expect("Node failed");
+        let replies: Vec<_> = std::iter::from_fn(|| from_node.try_recv().ok())
+            .map(|msg| msg.body.message["type"].clone())
+            .collect();
+        assert_eq!(replies, [json!("init_ok"), json!("broadcast_ok")]);
+    }
+
+    #[test]
+    fn test_seeded_tick_jitter_repeats() {
+        let period = Duration::from_millis(100);
+        let jitter = Duration::from_millis(50);
+        let delays = |seed| {
+            tick_delays(period, jitter, StdRng::seed_from_u64(seed))
+                .take(5)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(delays(3), delays(3));
+        assert_ne!(delays(3), delays(4));
+        assert!(delays(3)
+            .iter()
+            .all(|delay| (period..=period + jitter).contains(delay)));
+    }
+}
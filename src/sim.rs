@@ -0,0 +1,416 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::fmt::Debug;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+
+use crate::rpc::{Outbound, Rpc};
+use crate::runtime::{self, Context, Handler};
+use crate::{Message, MessageBody, Protocol};
+
+/// The client every simulated request comes from.
+const CLIENT: &str = "c1";
+/// How long `assert_converged` waits for each node to answer.
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How the simulated network treats messages between nodes. Messages to and
+/// from the client see the same latency but are never dropped or partitioned,
+/// like Maelstrom's own clients.
+#[derive(Debug, Clone)]
+pub struct Network {
+    pub min_latency: Duration,
+    pub max_latency: Duration,
+    /// Chance of any single message between nodes being lost.
+    pub drop_rate: f64,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            drop_rate: 0.0,
+        }
+    }
+}
+
+struct SimNode<H> {
+    handler: H,
+    ctx: Context,
+    outbound: mpsc::UnboundedReceiver<Outbound>,
+    next_tick: Option<Duration>,
+}
+
+/// A message in flight, ordered by delivery time and then by when it was sent.
+struct InFlight {
+    at: Duration,
+    seq: u64,
+    msg: Message<Value>,
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlight {
+    // Reversed, so that the max-heap pops the earliest message first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+/// Runs a cluster of handlers in-process on a virtual clock. Latency, drops
+/// and ticks all come from the clock and a seeded rng, which also seeds each
+/// node's `Context::rng`, so the same seed replays the same run.
+///
+/// Everything happens synchronously between `on_message` and `on_tick` calls,
+/// so handlers that spawn tasks (acked broadcast, the KV-backed workloads)
+/// aren't supported here.
+pub struct Simulation<H: Handler> {
+    network: Network,
+    rng: StdRng,
+    now: Duration,
+    seq: u64,
+    nodes: BTreeMap<String, SimNode<H>>,
+    in_flight: BinaryHeap<InFlight>,
+    /// Which side of a partition each node is on. Nodes missing here are all
+    /// on the same side.
+    sides: HashMap<String, usize>,
+    next_msg_id: usize,
+    replies: HashMap<usize, Value>,
+}
+
+impl<H: Handler> Simulation<H> {
+    /// Starts `count` nodes, named `n0`, `n1`, ..., each built by `new_node`
+    /// and already initialised.
+    pub fn new(seed: u64, network: Network, count: usize, new_node: impl Fn() -> H) -> Self {
+        let node_ids: Vec<String> = (0..count).map(|i| format!("n{}", i)).collect();
+        let mut sim = Simulation {
+            network,
+            rng: StdRng::seed_from_u64(seed),
+            now: Duration::ZERO,
+            seq: 0,
+            nodes: BTreeMap::new(),
+            in_flight: BinaryHeap::new(),
+            sides: HashMap::new(),
+            next_msg_id: 1,
+            replies: HashMap::new(),
+        };
+        for node_id in &node_ids {
+            let (rpc, outbound) = Rpc::new();
+            let handler = new_node();
+            let next_tick = handler.tick_interval();
+            let mut node = SimNode {
+                handler,
+                ctx: Context::seeded(rpc, sim.rng.gen()),
+                outbound,
+                next_tick,
+            };
+            let init = sim.client_message(
+                node_id,
+                Protocol::Init {
+                    node_id: node_id.clone(),
+                    node_ids: node_ids.clone(),
+                },
+            );
+            runtime::dispatch(&mut node.handler, &mut node.ctx, init);
+            sim.nodes.insert(node_id.clone(), node);
+            sim.flush(node_id);
+        }
+        sim
+    }
+
+    /// Time elapsed on the virtual clock.
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn node_ids(&self) -> impl Iterator<Item = &String> {
+        self.nodes.keys()
+    }
+
+    pub fn node(&self, node_id: &str) -> &H {
+        &self.nodes[node_id].handler
+    }
+
+    /// Splits the cluster so that messages only flow between nodes in the
+    /// same group. Nodes left out of every group form a group of their own.
+    pub fn partition(&mut self, groups: &[&[&str]]) {
+        self.sides.clear();
+        for (side, group) in groups.iter().enumerate() {
+            for node in group.iter() {
+                self.sides.insert(node.to_string(), side + 1);
+            }
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.sides.clear();
+    }
+
+    /// Sends `payload` from the client to `dest` without waiting for a reply.
+    /// Returns the request's msg_id.
+    pub fn request<P: Serialize>(&mut self, dest: &str, payload: P) -> usize {
+        let msg = self.client_message(dest, payload);
+        let msg_id = msg
+            .body
+            .msg_id
+            .expect("Client messages always have a msg_id");
+        self.transmit(msg);
+        msg_id
+    }
+
+    /// The reply to the client's request `msg_id`, if one has arrived.
+    pub fn reply<R: DeserializeOwned>(&self, msg_id: usize) -> Option<R> {
+        self.replies
+            .get(&msg_id)
+            .map(|reply| serde_json::from_value(reply.clone()).expect("Unexpected reply type"))
+    }
+
+    /// Sends `payload` from the client to `dest` and runs the cluster until
+    /// the reply comes back, or until `timeout` passes without one.
+    pub fn call<P: Serialize, R: DeserializeOwned>(
+        &mut self,
+        dest: &str,
+        payload: P,
+        timeout: Duration,
+    ) -> Option<R> {
+        let msg_id = self.request(dest, payload);
+        let deadline = self.now + timeout;
+        while !self.replies.contains_key(&msg_id) && self.step(deadline) {}
+        self.reply(msg_id)
+    }
+
+    /// Sends `read` to every node in turn and asserts that each of them
+    /// replies with `expected`.
+    pub fn assert_converged<P, R>(&mut self, read: P, expected: R)
+    where
+        P: Serialize + Clone,
+        R: DeserializeOwned + PartialEq + Debug,
+    {
+        let node_ids: Vec<String> = self.node_ids().cloned().collect();
+        for node in node_ids {
+            let reply: Option<R> = self.call(&node, read.clone(), READ_TIMEOUT);
+            assert_eq!(reply.as_ref(), Some(&expected), "{} did not converge", node);
+        }
+    }
+
+    /// Runs the cluster for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) {
+        let deadline = self.now + duration;
+        while self.step(deadline) {}
+    }
+
+    /// Delivers the next message or tick due by `deadline`. Returns false,
+    /// with the clock moved up to `deadline`, once there is nothing left.
+    fn step(&mut self, deadline: Duration) -> bool {
+        let next_message = self.in_flight.peek().map(|msg| msg.at);
+        let next_tick = self
+            .nodes
+            .iter()
+            .filter_map(|(node_id, node)| node.next_tick.map(|at| (at, node_id.clone())))
+            .min();
+
+        match (next_message, next_tick) {
+            // Messages go first when they are due at the same time as a tick
+            (Some(at), tick) if at <= deadline && tick.as_ref().is_none_or(|(t, _)| at <= *t) => {
+                let InFlight { at, msg, .. } = self.in_flight.pop().expect("Peeked a message");
+                self.now = at;
+                self.deliver(msg);
+            }
+            (_, Some((at, node_id))) if at <= deadline => {
+                self.now = at;
+                let node = self
+                    .nodes
+                    .get_mut(&node_id)
+                    .expect("Ticked an unknown node");
+                node.handler.on_tick(&node.ctx);
+                let jitter = node.handler.tick_jitter().mul_f64(self.rng.gen());
+                node.next_tick = node
+                    .handler
+                    .tick_interval()
+                    .map(|period| at + period + jitter);
+                self.flush(&node_id);
+            }
+            _ => {
+                self.now = self.now.max(deadline);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn deliver(&mut self, msg: Message<Value>) {
+        let dest = msg.dest.clone();
+        match self.nodes.get_mut(&dest) {
+            Some(node) => {
+                runtime::dispatch(&mut node.handler, &mut node.ctx, msg);
+                self.flush(&dest);
+            }
+            None => {
+                if let Some(in_reply_to) = msg.body.in_reply_to {
+                    self.replies.insert(in_reply_to, msg.body.message);
+                }
+            }
+        }
+    }
+
+    /// Puts everything `node_id` has queued for sending onto the network.
+    fn flush(&mut self, node_id: &str) {
+        let node = self
+            .nodes
+            .get_mut(node_id)
+            .expect("Flushed an unknown node");
+        let mut outbound = Vec::new();
+        while let Ok(msg) = node.outbound.try_recv() {
+            outbound.push(msg);
+        }
+        for Outbound { dest, body } in outbound {
+            self.transmit(Message {
+                src: node_id.to_string(),
+                dest,
+                body,
+            });
+        }
+    }
+
+    fn transmit(&mut self, msg: Message<Value>) {
+        let between_nodes = self.nodes.contains_key(&msg.src) && self.nodes.contains_key(&msg.dest);
+        if between_nodes {
+            let side = |node: &String| self.sides.get(node).copied().unwrap_or_default();
+            if side(&msg.src) != side(&msg.dest) {
+                return;
+            }
+            if self.rng.gen_bool(self.network.drop_rate) {
+                return;
+            }
+        }
+        let latency = self
+            .rng
+            .gen_range(self.network.min_latency..=self.network.max_latency);
+        self.seq += 1;
+        self.in_flight.push(InFlight {
+            at: self.now + latency,
+            seq: self.seq,
+            msg,
+        });
+    }
+
+    fn client_message<P: Serialize>(&mut self, dest: &str, payload: P) -> Message<Value> {
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        Message {
+            src: CLIENT.to_string(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                message: serde_json::to_value(payload).expect("Failed to convert payload to JSON."),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::*;
+    use crate::broadcast::{BroadcastMode, BroadcastNode, Payload};
+    use crate::config::{Exchange, GossipConfig};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn read(sim: &mut Simulation<BroadcastNode>, node: &str) -> HashSet<usize> {
+        match sim.call(node, Payload::Read, TIMEOUT) {
+            Some(Payload::ReadOk { messages }) => messages,
+            other => panic!("Unexpected read reply: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_broadcast_converges_after_partition() {
+        let network = Network {
+            drop_rate: 0.2,
+            ..Default::default()
+        };
+        for exchange in [
+            Exchange::Push,
+            Exchange::Pull,
+            Exchange::PushPull,
+            Exchange::Digest,
+            Exchange::Merkle,
+        ] {
+            let config = GossipConfig {
+                jitter: Duration::from_millis(50),
+                exchange,
+                // Every round only carries part of what's missing
+                max_payload: Some(5),
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(7, network.clone(), 5, || {
+                BroadcastNode::new(BroadcastMode::Gossip, config.clone())
+            });
+
+            // A line, n0 - n1 - n2 - n3 - n4
+            let node_ids: Vec<String> = sim.node_ids().cloned().collect();
+            let mut topology: HashMap<String, HashSet<String>> = HashMap::new();
+            for pair in node_ids.windows(2) {
+                topology
+                    .entry(pair[0].clone())
+                    .or_default()
+                    .insert(pair[1].clone());
+                topology
+                    .entry(pair[1].clone())
+                    .or_default()
+                    .insert(pair[0].clone());
+            }
+            for node in &node_ids {
+                let reply: Option<Payload> = sim.call(
+                    node,
+                    Payload::Topology {
+                        topology: topology.clone(),
+                    },
+                    TIMEOUT,
+                );
+                assert_eq!(reply, Some(Payload::TopologyOk));
+            }
+
+            sim.partition(&[&["n0", "n1"], &["n2", "n3", "n4"]]);
+            for message in 0..30 {
+                let node = &node_ids[message % node_ids.len()];
+                let reply: Option<Payload> =
+                    sim.call(node, Payload::Broadcast { message }, TIMEOUT);
+                assert_eq!(reply, Some(Payload::BroadcastOk));
+            }
+            sim.run_for(Duration::from_secs(5));
+            assert!(
+                read(&mut sim, "n0").is_disjoint(&read(&mut sim, "n4")),
+                "Values crossed the partition with {:?}",
+                exchange
+            );
+
+            // Pulls across the cut came back empty while it was up, so each
+            // hop may be several backed off periods away from its next one
+            sim.heal();
+            sim.run_for(Duration::from_secs(20));
+            let messages = (0..30).collect();
+            sim.assert_converged(Payload::Read, Payload::ReadOk { messages });
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use tokio::time::Duration;
+
+/// A hashed timer wheel. Each timer sits in the slot its deadline hashes to,
+/// so scheduling and expiring cost the same however many timers are pending.
+/// Time only moves when `advance` is called, which should happen once every
+/// `resolution`.
+pub struct TimerWheel<K> {
+    resolution: Duration,
+    slots: Vec<Vec<K>>,
+    /// The tick each pending timer fires on. Slots may still hold keys that
+    /// were rescheduled since, which are skipped when their slot comes up.
+    deadlines: HashMap<K, u64>,
+    now: u64,
+}
+
+impl<K: Hash + Eq + Clone> TimerWheel<K> {
+    pub fn new(resolution: Duration, slots: usize) -> Self {
+        assert!(!resolution.is_zero(), "Timer resolution can't be 0");
+        assert!(slots > 0, "A timer wheel needs at least one slot");
+        TimerWheel {
+            resolution,
+            slots: vec![Vec::new(); slots],
+            deadlines: HashMap::new(),
+            now: 0,
+        }
+    }
+
+    pub fn resolution(&self) -> Duration {
+        self.resolution
+    }
+
+    /// Fires `key` after `delay`, rounded up to a whole number of ticks and
+    /// never sooner than the next one. Replaces any timer `key` already had.
+    pub fn schedule(&mut self, key: K, delay: Duration) {
+        let ticks = delay.as_nanos().div_ceil(self.resolution.as_nanos()).max(1);
+        let deadline = self
+            .now
+            .saturating_add(u64::try_from(ticks).unwrap_or(u64::MAX));
+        let slot = self.slot(deadline);
+        self.deadlines.insert(key.clone(), deadline);
+        self.slots[slot].push(key);
+    }
+
+    pub fn is_scheduled(&self, key: &K) -> bool {
+        self.deadlines.contains_key(key)
+    }
+
+    /// Moves time forward by one tick and returns the keys whose timers fired,
+    /// in the order they were scheduled.
+    pub fn advance(&mut self) -> Vec<K> {
+        self.now += 1;
+        let slot = self.slot(self.now);
+        let mut fired = Vec::new();
+        let mut later = Vec::new();
+        for key in std::mem::take(&mut self.slots[slot]) {
+            match self.deadlines.get(&key) {
+                Some(&deadline) if deadline == self.now => {
+                    self.deadlines.remove(&key);
+                    fired.push(key);
+                }
+                // Due on a later turn of the wheel
+                Some(&deadline) if self.slot(deadline) == slot && !later.contains(&key) => {
+                    later.push(key)
+                }
+                // Rescheduled into another slot, or already fired
+                _ => {}
+            }
+        }
+        self.slots[slot] = later;
+        fired
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_fire_on_their_tick() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(10), 4);
+        wheel.schedule("a", Duration::from_millis(25));
+        wheel.schedule("b", Duration::ZERO);
+        // Further out than the wheel is wide
+        wheel.schedule("c", Duration::from_millis(90));
+        wheel.schedule("d", Duration::from_millis(10));
+        // Only the latest deadline counts
+        wheel.schedule("d", Duration::from_millis(50));
+
+        let fired: Vec<Vec<&str>> = (0..10).map(|_| wheel.advance()).collect();
+        assert_eq!(
+            fired,
+            vec![
+                vec!["b"],
+                vec![],
+                vec!["a"],
+                vec![],
+                vec!["d"],
+                vec![],
+                vec![],
+                vec![],
+                vec!["c"],
+                vec![],
+            ]
+        );
+        assert!(!wheel.is_scheduled(&"c"));
+    }
+}
@@ -0,0 +1,261 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::str::FromStr;
+
+/// Each node's neighbours. Every strategy here builds undirected graphs, so
+/// if `a` lists `b` then `b` lists `a`.
+pub type Topology = HashMap<String, HashSet<String>>;
+
+/// How a node decides who its neighbours are. Every node must be configured
+/// the same way, since each one computes the whole topology on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TopologyStrategy {
+    /// Whatever Maelstrom suggests in its `topology` message.
+    #[default]
+    Maelstrom,
+    /// Every node talks to the first node, and only to it.
+    Star,
+    /// A balanced tree where every node has up to `k` children.
+    Tree(usize),
+    /// Every node talks to the nodes before and after it.
+    Ring,
+    /// A ring with chords to the nodes 2, 4, 8, ... places further along,
+    /// which keeps the diameter logarithmic in the cluster size.
+    SmallWorld,
+    /// A spanning tree of Maelstrom's suggested topology with the smallest
+    /// possible diameter.
+    SpanningTree,
+}
+
+impl FromStr for TopologyStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "maelstrom" => Ok(TopologyStrategy::Maelstrom),
+            "star" => Ok(TopologyStrategy::Star),
+            "ring" => Ok(TopologyStrategy::Ring),
+            "small-world" => Ok(TopologyStrategy::SmallWorld),
+            "spanning-tree" => Ok(TopologyStrategy::SpanningTree),
+            other => match other.strip_prefix("tree:").map(str::parse) {
+                Some(Ok(k)) if k > 0 => Ok(TopologyStrategy::Tree(k)),
+                _ => Err(format!("Unknown topology: {}", other)),
+            },
+        }
+    }
+}
+
+impl TopologyStrategy {
+    /// Builds the topology for `node_ids`. Only `Maelstrom` and
+    /// `SpanningTree` look at the `suggested` one.
+    pub fn build(&self, node_ids: &[String], suggested: &Topology) -> Topology {
+        let mut nodes = node_ids.to_vec();
+        nodes.sort();
+        let n = nodes.len();
+        let mut topology = Topology::new();
+        match self {
+            TopologyStrategy::Maelstrom => return suggested.clone(),
+            TopologyStrategy::Star => {
+                for node in nodes.iter().skip(1) {
+                    connect(&mut topology, &nodes[0], node);
+                }
+            }
+            TopologyStrategy::Tree(k) => {
+                for (i, node) in nodes.iter().enumerate().skip(1) {
+                    connect(&mut topology, &nodes[(i - 1) / k], node);
+                }
+            }
+            TopologyStrategy::Ring => {
+                for (i, node) in nodes.iter().enumerate() {
+                    connect(&mut topology, node, &nodes[(i + 1) % n]);
+                }
+            }
+            TopologyStrategy::SmallWorld => {
+                for (i, node) in nodes.iter().enumerate() {
+                    let mut step = 1;
+                    while step < n {
+                        connect(&mut topology, node, &nodes[(i + step) % n]);
+                        step *= 2;
+                    }
+                }
+            }
+            TopologyStrategy::SpanningTree => {
+                let roots = absolute_centre(suggested);
+                if let [a, b] = roots.as_slice() {
+                    connect(&mut topology, a, b);
+                }
+                for (node, parent) in bfs_parents(suggested, &roots) {
+                    connect(&mut topology, &parent, &node);
+                }
+            }
+        }
+        // A lone node, or a ring of one, shouldn't list itself
+        for (node, neighbours) in topology.iter_mut() {
+            neighbours.remove(node);
+        }
+        topology
+    }
+}
+
+fn connect(topology: &mut Topology, a: &str, b: &str) {
+    topology
+        .entry(a.to_string())
+        .or_default()
+        .insert(b.to_string());
+    topology
+        .entry(b.to_string())
+        .or_default()
+        .insert(a.to_string());
+}
+
+/// Hops from `from` to every node it can reach.
+fn bfs<'a>(topology: &'a Topology, from: &'a str) -> HashMap<&'a str, usize> {
+    let mut distances = HashMap::from([(from, 0)]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(node) = queue.pop_front() {
+        let distance = distances[node];
+        for neighbour in topology.get(node).into_iter().flatten() {
+            if !distances.contains_key(neighbour.as_str()) {
+                distances.insert(neighbour, distance + 1);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    distances
+}
+
+/// The point of `topology` whose furthest node is the nearest, counting every
+/// edge as one unit long: a single node, or the two ends of the edge whose
+/// middle it is.
+/// The breadth-first tree grown from there is a spanning tree of the smallest
+/// possible diameter (Hassin and Tamir), twice the point's eccentricity.
+/// Ties go to the first candidate in order, so that every node picks the same.
+fn absolute_centre<'a>(topology: &'a Topology) -> Vec<&'a str> {
+    let mut nodes: Vec<&str> = topology.keys().map(String::as_str).collect();
+    nodes.sort();
+    let distances: HashMap<&str, HashMap<&str, usize>> = nodes
+        .iter()
+        .map(|node| (*node, bfs(topology, node)))
+        .collect();
+
+    // Eccentricities are doubled, so that edge midpoints stay whole
+    let mut best: Option<(usize, Vec<&str>)> = None;
+    let mut consider = |eccentricity: usize, roots: Vec<&'a str>| {
+        if best.as_ref().is_none_or(|(min, _)| eccentricity < *min) {
+            best = Some((eccentricity, roots));
+        }
+    };
+    for node in &nodes {
+        let eccentricity = distances[node].values().max().copied().unwrap_or_default();
+        consider(2 * eccentricity, vec![*node]);
+    }
+    for a in &nodes {
+        let mut neighbours: Vec<&str> = topology[*a].iter().map(String::as_str).collect();
+        neighbours.sort();
+        for b in neighbours.into_iter().filter(|b| a < b) {
+            // Whichever end is closer takes the node, plus half the edge
+            let eccentricity = distances[a]
+                .iter()
+                .map(|(node, distance)| {
+                    let other = distances[b].get(node).copied().unwrap_or(usize::MAX);
+                    (*distance).min(other)
+                })
+                .max()
+                .unwrap_or_default();
+            consider(2 * eccentricity + 1, vec![*a, b]);
+        }
+    }
+    best.map(|(_, roots)| roots).unwrap_or_default()
+}
+
+/// Each node's parent in the breadth-first tree grown from `roots`.
+/// Neighbours are visited in order, so that every node builds the same tree.
+fn bfs_parents(topology: &Topology, roots: &[&str]) -> Vec<(String, String)> {
+    let mut parents = Vec::new();
+    let mut visited: HashSet<String> = roots.iter().map(|root| root.to_string()).collect();
+    let mut queue: VecDeque<String> = roots.iter().map(|root| root.to_string()).collect();
+    while let Some(node) = queue.pop_front() {
+        let mut neighbours: Vec<&String> = topology.get(&node).into_iter().flatten().collect();
+        neighbours.sort();
+        for neighbour in neighbours {
+            if visited.insert(neighbour.clone()) {
+                parents.push((neighbour.clone(), node.clone()));
+                queue.push_back(neighbour.clone());
+            }
+        }
+    }
+    parents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The `side` x `side` grid Maelstrom suggests for `side * side` nodes.
+    fn grid(node_ids: &[String], side: usize) -> Topology {
+        let mut topology = Topology::new();
+        for i in 0..side * side {
+            if i % side < side - 1 {
+                connect(&mut topology, &node_ids[i], &node_ids[i + 1]);
+            }
+            if i < side * (side - 1) {
+                connect(&mut topology, &node_ids[i], &node_ids[i + side]);
+            }
+        }
+        topology
+    }
+
+    fn diameter(topology: &Topology) -> usize {
+        topology
+            .keys()
+            .map(|node| {
+                let distances = bfs(topology, node);
+                assert_eq!(distances.len(), topology.len(), "Topology is disconnected");
+                *distances.values().max().unwrap()
+            })
+            .max()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_strategies_build_connected_graphs() {
+        let node_ids: Vec<String> = (0..25).map(|i| format!("n{}", i)).collect();
+        let grid = grid(&node_ids, 5);
+        let edges = |topology: &Topology| topology.values().map(HashSet::len).sum::<usize>() / 2;
+
+        let star = TopologyStrategy::Star.build(&node_ids, &grid);
+        assert_eq!((edges(&star), diameter(&star)), (24, 2));
+
+        let tree = TopologyStrategy::Tree(4).build(&node_ids, &grid);
+        assert_eq!((edges(&tree), diameter(&tree)), (24, 5));
+
+        let ring = TopologyStrategy::Ring.build(&node_ids, &grid);
+        assert_eq!((edges(&ring), diameter(&ring)), (25, 12));
+
+        let small_world = TopologyStrategy::SmallWorld.build(&node_ids, &grid);
+        assert!(diameter(&small_world) <= 3);
+
+        let spanning_tree = TopologyStrategy::SpanningTree.build(&node_ids, &grid);
+        assert_eq!((edges(&spanning_tree), diameter(&spanning_tree)), (24, 8));
+        for (node, neighbours) in &spanning_tree {
+            assert!(neighbours.is_subset(&grid[node]), "{} left the grid", node);
+        }
+
+        assert_eq!(TopologyStrategy::Maelstrom.build(&node_ids, &grid), grid);
+        assert_eq!("tree:3".parse(), Ok(TopologyStrategy::Tree(3)));
+        assert!("tree:0".parse::<TopologyStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_spanning_tree_can_grow_from_an_edge() {
+        // A square n0 - n1 - n3 - n4 with n2 hanging off n3. No node is within
+        // one hop of all the others, but the middle of n1 - n3 is within 1.5
+        let node_ids: Vec<String> = (0..5).map(|i| format!("n{}", i)).collect();
+        let mut square = Topology::new();
+        for (a, b) in [(0, 1), (1, 3), (3, 4), (4, 0), (3, 2)] {
+            connect(&mut square, &node_ids[a], &node_ids[b]);
+        }
+        let spanning_tree = TopologyStrategy::SpanningTree.build(&node_ids, &square);
+        assert_eq!(spanning_tree.values().map(HashSet::len).sum::<usize>(), 8);
+        assert_eq!(diameter(&spanning_tree), 3);
+    }
+}
@@ -0,0 +1,495 @@
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use serde_json::Value;
+use tokio::io::{self, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{LinesStream, UnboundedReceiverStream};
+use tokio_stream::{Stream, StreamExt};
+use tracing::warn;
+
+use crate::error::Error;
+use crate::writer::BatchWriter;
+use crate::{Message, MessageBody, Protocol};
+
+/// Messages arriving at a node, in the order they arrived.
+pub type Incoming = Pin<Box<dyn Stream<Item = Result<Message<Value>, Error>> + Send>>;
+
+/// Where a node's messages come from and go to. The runtime only ever sees
+/// whole messages, so the same handler can be served over Maelstrom's stdio
+/// protocol, over sockets, or in-memory in tests.
+pub trait Transport {
+    type Sink: MessageSink;
+
+    fn split(self) -> (Incoming, Self::Sink);
+}
+
+/// The outgoing half of a `Transport`.
+pub trait MessageSink {
+    fn send(&mut self, msg: Message<Value>) -> impl Future<Output = Result<(), Error>>;
+
+    /// Waits until everything sent so far has gone out.
+    fn flush(&mut self) -> impl Future<Output = Result<(), Error>> {
+        async { Ok(()) }
+    }
+}
+
+/// Maelstrom's protocol: one JSON message per line on stdin and stdout.
+/// Output goes through a `BatchWriter`.
+pub struct Stdio {
+    writer: BatchWriter,
+}
+
+impl Stdio {
+    /// Lets up to `capacity` messages queue up for stdout.
+    pub fn new(capacity: usize) -> Self {
+        Stdio {
+            writer: BatchWriter::spawn(io::stdout(), capacity),
+        }
+    }
+
+    /// The writer behind stdout, kept around to watch its stats.
+    pub fn writer(&self) -> &BatchWriter {
+        &self.writer
+    }
+}
+
+impl Transport for Stdio {
+    type Sink = BatchWriter;
+
+    fn split(self) -> (Incoming, Self::Sink) {
+        let lines = LinesStream::new(BufReader::new(io::stdin()).lines());
+        let incoming = lines.map(|line| parse(line?));
+        (Box::pin(incoming), self.writer)
+    }
+}
+
+/// Parses one line of input. A line that isn't a message is reported along
+/// with the line itself, so the node can log it and carry on.
+fn parse(line: String) -> Result<Message<Value>, Error> {
+    serde_json::from_str(&line).map_err(|source| Error::MalformedMessage { line, source })
+}
+
+/// An in-memory transport, for driving a node from tests.
+pub struct Channel {
+    incoming: mpsc::UnboundedReceiver<Message<Value>>,
+    outgoing: mpsc::UnboundedSender<Message<Value>>,
+}
+
+impl Channel {
+    /// Returns the transport along with the sender for messages to the node
+    /// and the receiver for everything it sends.
+    pub fn new() -> (
+        Self,
+        mpsc::UnboundedSender<Message<Value>>,
+        mpsc::UnboundedReceiver<Message<Value>>,
+    ) {
+        let (to_node, incoming) = mpsc::unbounded_channel();
+        let (outgoing, from_node) = mpsc::unbounded_channel();
+        (Channel { incoming, outgoing }, to_node, from_node)
+    }
+}
+
+impl Transport for Channel {
+    type Sink = mpsc::UnboundedSender<Message<Value>>;
+
+    fn split(self) -> (Incoming, Self::Sink) {
+        let incoming = UnboundedReceiverStream::new(self.incoming).map(Ok);
+        (Box::pin(incoming), self.outgoing)
+    }
+}
+
+impl MessageSink for mpsc::UnboundedSender<Message<Value>> {
+    async fn send(&mut self, msg: Message<Value>) -> Result<(), Error> {
+        mpsc::UnboundedSender::send(self, msg).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Channel receiver dropped").into()
+        })
+    }
+}
+
+/// Where a node listens: `host:port` for TCP, or `unix:<path>` for a Unix
+/// socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("Empty Unix socket path".to_string()),
+            Some(path) => Ok(Address::Unix(PathBuf::from(path))),
+            None if s.contains(':') => Ok(Address::Tcp(s.to_string())),
+            None => Err(format!("Not a host:port or unix:<path> address: {}", s)),
+        }
+    }
+}
+
+/// Who sends the `init` message in a socket cluster. Nothing listens under
+/// this name, so the `init_ok` is dropped.
+const CLUSTER: &str = "cluster";
+
+/// A node's place in a cluster run over sockets instead of Maelstrom: its own
+/// id and every node's address, its own included.
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    pub node_id: String,
+    pub addresses: BTreeMap<String, Address>,
+}
+
+impl Cluster {
+    /// Reads the cluster from `FESTROM_NODE_ID` and `FESTROM_CLUSTER`, the
+    /// latter a comma-separated list of `node=address` pairs. Returns `None`
+    /// when they aren't set, in which case the node speaks Maelstrom's stdio
+    /// protocol instead.
+    pub fn from_env() -> Result<Option<Self>, Error> {
+        let (Ok(node_id), Ok(cluster)) = (
+            std::env::var("FESTROM_NODE_ID"),
+            std::env::var("FESTROM_CLUSTER"),
+        ) else {
+            return Ok(None);
+        };
+        let addresses = cluster
+            .split(',')
+            .map(|entry| {
+                let (node, address) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Expected node=address, got {}", entry))?;
+                Ok((node.trim().to_string(), address.trim().parse()?))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()
+            .map_err(Error::ConfigError)?;
+        if !addresses.contains_key(&node_id) {
+            return Err(Error::ConfigError(format!(
+                "{} is not part of FESTROM_CLUSTER",
+                node_id
+            )));
+        }
+        Ok(Some(Cluster { node_id, addresses }))
+    }
+
+    /// Starts listening on this node's address.
+    pub async fn bind(self) -> Result<Socket, Error> {
+        let listener = match &self.addresses[&self.node_id] {
+            Address::Tcp(address) => Listener::Tcp(TcpListener::bind(address).await?),
+            Address::Unix(path) => {
+                // A previous run may have left its socket behind
+                let _ = std::fs::remove_file(path);
+                Listener::Unix(UnixListener::bind(path)?)
+            }
+        };
+        Ok(Socket {
+            cluster: self,
+            listener,
+        })
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Lines waiting to be written to one connection, by whoever we talk to over it.
+type Routes = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>;
+
+/// Newline-delimited JSON over TCP or Unix sockets. Nodes connect to each
+/// other on first send, and anyone else (a client, say) gets replies over the
+/// connection their messages came in on. The node inits itself from the
+/// cluster it was bound with.
+pub struct Socket {
+    cluster: Cluster,
+    listener: Listener,
+}
+
+impl Transport for Socket {
+    type Sink = SocketSink;
+
+    fn split(self) -> (Incoming, Self::Sink) {
+        let routes = Routes::default();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(accept(self.listener, routes.clone(), tx.clone()));
+
+        let init = Message {
+            src: CLUSTER.to_string(),
+            dest: self.cluster.node_id.clone(),
+            body: MessageBody {
+                msg_id: None,
+                in_reply_to: None,
+                message: serde_json::to_value(Protocol::Init {
+                    node_id: self.cluster.node_id,
+                    node_ids: self.cluster.addresses.keys().cloned().collect(),
+                })
+                .expect("Failed to convert init to JSON."),
+            },
+        };
+        let incoming = tokio_stream::once(Ok(init)).chain(UnboundedReceiverStream::new(rx));
+        let sink = SocketSink {
+            addresses: self.cluster.addresses,
+            routes,
+            incoming: tx,
+        };
+        (Box::pin(incoming), sink)
+    }
+}
+
+pub struct SocketSink {
+    addresses: BTreeMap<String, Address>,
+    routes: Routes,
+    incoming: mpsc::UnboundedSender<Result<Message<Value>, Error>>,
+}
+
+impl SocketSink {
+    async fn connect(&self, address: &Address) -> std::io::Result<mpsc::UnboundedSender<String>> {
+        let routes = self.routes.clone();
+        let incoming = self.incoming.clone();
+        Ok(match address {
+            Address::Tcp(address) => attach(TcpStream::connect(address).await?, routes, incoming),
+            Address::Unix(path) => attach(UnixStream::connect(path).await?, routes, incoming),
+        })
+    }
+}
+
+impl MessageSink for SocketSink {
+    async fn send(&mut self, msg: Message<Value>) -> Result<(), Error> {
+        let line = format!("{}\n", serde_json::to_string(&msg)?);
+        let route = self
+            .routes
+            .lock()
+            .expect("Routes lock poisoned")
+            .remove(&msg.dest);
+        if let Some(route) = route {
+            if route.send(line.clone()).is_ok() {
+                self.routes
+                    .lock()
+                    .expect("Routes lock poisoned")
+                    .insert(msg.dest, route);
+                return Ok(());
+            }
+        }
+
+        // Peers being down is just another network failure, so we only log it
+        let Some(address) = self.addresses.get(&msg.dest) else {
+            warn!(dest = %msg.dest, "No route, dropping message");
+            return Ok(());
+        };
+        match self.connect(address).await {
+            Ok(route) => {
+                let _ = route.send(line);
+                self.routes
+                    .lock()
+                    .expect("Routes lock poisoned")
+                    .insert(msg.dest, route);
+            }
+            Err(err) => warn!(dest = %msg.dest, %err, "Failed to connect"),
+        }
+        Ok(())
+    }
+}
+
+async fn accept(
+    listener: Listener,
+    routes: Routes,
+    incoming: mpsc::UnboundedSender<Result<Message<Value>, Error>>,
+) {
+    loop {
+        let (routes, incoming) = (routes.clone(), incoming.clone());
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| attach(stream, routes, incoming)),
+            Listener::Unix(listener) => listener
+                .accept()
+                .await
+                .map(|(stream, _)| attach(stream, routes, incoming)),
+        };
+        if let Err(err) = accepted {
+            warn!(%err, "Failed to accept a connection");
+        }
+    }
+}
+
+/// Starts reading messages off `stream` and returns the sender for lines to
+/// write to it. Whoever messages us over a connection is answered over it too,
+/// unless we already talk to them over another one.
+fn attach<S>(
+    stream: S,
+    routes: Routes,
+    incoming: mpsc::UnboundedSender<Result<Message<Value>, Error>>,
+) -> mpsc::UnboundedSender<String>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (read, mut write) = io::split(stream);
+    let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+    tokio::spawn(async move {
+        while let Some(line) = rx.recv().await {
+            if write.write_all(line.as_bytes()).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    let writer = tx.clone();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(read).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            let msg = parse(line);
+            if let Ok(msg) = &msg {
+                routes
+                    .lock()
+                    .expect("Routes lock poisoned")
+                    .entry(msg.src.clone())
+                    .or_insert_with(|| writer.clone());
+            }
+            if incoming.send(msg).is_err() {
+                return;
+            }
+        }
+        routes
+            .lock()
+            .expect("Routes lock poisoned")
+            .retain(|_, route| !route.same_channel(&writer));
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use super::*;
+    use crate::runtime::{self, Context, Handler};
+
+    #[derive(Serialize, Deserialize, Debug)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Payload {
+        Echo { echo: String },
+        EchoOk { echo: String },
+    }
+
+    struct Echo;
+
+    impl Handler for Echo {
+        type Payload = Payload;
+
+        fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+            if let Payload::Echo { echo } = &msg.body.message {
+                ctx.reply(&msg, Payload::EchoOk { echo: echo.clone() });
+            }
+        }
+    }
+
+    fn message(src: &str, dest: &str, msg_id: usize, message: Value) -> Message<Value> {
+        Message {
+            src: src.to_string(),
+            dest: dest.to_string(),
+            body: MessageBody {
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+                message,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_transport() {
+        let (transport, to_node, mut from_node) = Channel::new();
+        let node = tokio::spawn(runtime::serve(Echo, transport));
+
+        let init = json!({"type": "init", "node_id": "n1", "node_ids": ["n1"]});
+        to_node.send(message("c1", "n1", 1, init)).unwrap();
+        let reply = from_node.recv().await.expect("No init_ok");
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert_eq!(reply.body.message, json!({"type": "init_ok"}));
+
+        let echo = json!({"type": "echo", "echo": "hi"});
+        to_node.send(message("c1", "n1", 2, echo)).unwrap();
+        let reply = from_node.recv().await.expect("No echo_ok");
+        assert_eq!(reply.dest, "c1");
+        assert_eq!(reply.body.message, json!({"type": "echo_ok", "echo": "hi"}));
+        node.abort();
+    }
+
+    /// Feeds raw lines to the node, as if they came in on stdin.
+    struct Lines(Vec<&'static str>, mpsc::UnboundedSender<Message<Value>>);
+
+    impl Transport for Lines {
+        type Sink = mpsc::UnboundedSender<Message<Value>>;
+
+        fn split(self) -> (Incoming, Self::Sink) {
+            let lines = self.0.into_iter().map(|line| parse(line.to_string()));
+            let incoming = tokio_stream::iter(lines).chain(tokio_stream::pending());
+            (Box::pin(incoming), self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_malformed_lines_are_rejected() {
+        let (tx, mut from_node) = mpsc::unbounded_channel();
+        let lines = vec![
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "init", "msg_id": 1, "node_id": "n1", "node_ids": ["n1"]}}"#,
+            r#"this isn't json"#,
+            r#"{"src": "c1", "body": {"type": "echo", "msg_id": 2, "echo": "no dest"}}"#,
+            r#"{"src": "c1", "dest": "n1", "body": {"type": "echo", "msg_id": 3, "echo": "hi"}}"#,
+        ];
+        let node = tokio::spawn(runtime::serve(Echo, Lines(lines, tx)));
+
+        let init_ok = from_node.recv().await.expect("No init_ok");
+        assert_eq!(init_ok.body.message, json!({"type": "init_ok"}));
+
+        // Nothing can be recovered from the second line, so only the third is rejected
+        let rejected = from_node.recv().await.expect("No error reply");
+        assert_eq!(rejected.dest, "c1");
+        assert_eq!(rejected.body.in_reply_to, Some(2));
+        assert_eq!(rejected.body.message["type"], "error");
+        assert_eq!(rejected.body.message["code"], 12);
+
+        let echo_ok = from_node
+            .recv()
+            .await
+            .expect("Node stopped after bad input");
+        assert_eq!(echo_ok.body.in_reply_to, Some(3));
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_unix_socket_cluster() {
+        let dir = std::env::temp_dir().join(format!("festrom-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("n1.sock");
+        let cluster = Cluster {
+            node_id: "n1".to_string(),
+            addresses: BTreeMap::from([("n1".to_string(), Address::Unix(path.clone()))]),
+        };
+        let socket = cluster.bind().await.expect("Failed to bind");
+        let node = tokio::spawn(runtime::serve(Echo, socket));
+
+        let (read, mut write) = io::split(UnixStream::connect(&path).await.unwrap());
+        let echo = message("c1", "n1", 1, json!({"type": "echo", "echo": "hi"}));
+        let line = format!("{}\n", serde_json::to_string(&echo).unwrap());
+        write.write_all(line.as_bytes()).await.unwrap();
+
+        let mut lines = BufReader::new(read).lines();
+        let reply = lines.next_line().await.unwrap().expect("Connection closed");
+        let reply: Message<Value> = serde_json::from_str(&reply).unwrap();
+        assert_eq!(reply.src, "n1");
+        assert_eq!(reply.body.in_reply_to, Some(1));
+        assert_eq!(reply.body.message, json!({"type": "echo_ok", "echo": "hi"}));
+
+        node.abort();
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,424 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use rand::prelude::IteratorRandom;
+use serde::{Deserialize, Serialize};
+use tokio::time::Duration;
+
+use crate::config::{GossipConfig, PeerSelection};
+use crate::replication::ReplicatedLog;
+use crate::runtime::{Context, Handler};
+use crate::topology::Topology;
+use crate::Message;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum Payload {
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    // Replicates transaction writes to other nodes: `writes` follow the
+    // first `from` writes in the sender's log, and `ack` is how much of the
+    // receiver's log the sender has without gaps, as in a broadcast delta
+    TxnGossip {
+        from: usize,
+        writes: Vec<Write>,
+        ack: usize,
+    },
+}
+
+/// The kind of a transaction micro-operation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// A single `[op, key, value]` step of a transaction. Reads come in with a
+/// `null` value and go back out with whatever they observed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MicroOp(pub Op, pub usize, pub Option<usize>);
+
+/// Lamport timestamp of a write, with the writing node as the tie-breaker.
+/// Registers keep whichever write has the greatest version.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u64, pub String);
+
+/// A write as replicated between nodes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Write {
+    pub key: usize,
+    pub value: usize,
+    pub version: Version,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Isolation {
+    /// Every write is applied and replicated as soon as it executes.
+    ReadUncommitted,
+    /// Only the final write of each key in a transaction is ever visible, and
+    /// all of a transaction's writes become visible together.
+    #[default]
+    ReadCommitted,
+}
+
+impl Isolation {
+    /// Reads the isolation level from `FESTROM_ISOLATION`, falling back to
+    /// read committed.
+    pub fn from_env() -> Self {
+        std::env::var("FESTROM_ISOLATION")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or_default()
+    }
+}
+
+impl FromStr for Isolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-uncommitted" => Ok(Isolation::ReadUncommitted),
+            "read-committed" => Ok(Isolation::ReadCommitted),
+            other => Err(format!("Unknown isolation level: {}", other)),
+        }
+    }
+}
+
+/// Node-local last-writer-wins registers serving the txn-rw-register workload.
+/// Because every transaction is stamped with a Lamport clock that has seen all
+/// the writes it read, both write-write and write-read dependencies always
+/// point forwards in version order and can't form cycles.
+#[derive(Debug, Default)]
+pub struct Store {
+    isolation: Isolation,
+    node_id: String,
+    clock: u64,
+    registers: HashMap<usize, (Version, usize)>,
+    /// Writes that changed a register here, local or merged, since they
+    /// were last handed over for replication.
+    unreplicated: Vec<Write>,
+}
+
+impl Store {
+    pub fn new(isolation: Isolation) -> Self {
+        Store {
+            isolation,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_node_id(&mut self, node_id: String) {
+        self.node_id = node_id;
+    }
+
+    fn next_version(&mut self) -> Version {
+        self.clock += 1;
+        Version(self.clock, self.node_id.clone())
+    }
+
+    fn read(&self, key: usize) -> Option<usize> {
+        self.registers.get(&key).map(|(_, value)| *value)
+    }
+
+    /// Runs `txn` against the local registers and returns it with reads filled in.
+    pub fn execute(&mut self, txn: Vec<MicroOp>) -> Vec<MicroOp> {
+        match self.isolation {
+            Isolation::ReadUncommitted => txn
+                .into_iter()
+                .map(|MicroOp(op, key, value)| match (op, value) {
+                    (Op::Write, Some(value)) => {
+                        let version = self.next_version();
+                        self.apply(Write {
+                            key,
+                            value,
+                            version,
+                        });
+                        MicroOp(op, key, Some(value))
+                    }
+                    _ => MicroOp(op, key, self.read(key)),
+                })
+                .collect(),
+            Isolation::ReadCommitted => {
+                let mut uncommitted = HashMap::new();
+                let txn = txn
+                    .into_iter()
+                    .map(|MicroOp(op, key, value)| match (op, value) {
+                        (Op::Write, Some(value)) => {
+                            uncommitted.insert(key, value);
+                            MicroOp(op, key, Some(value))
+                        }
+                        _ => {
+                            let value = uncommitted.get(&key).copied().or(self.read(key));
+                            MicroOp(op, key, value)
+                        }
+                    })
+                    .collect();
+                let version = self.next_version();
+                for (key, value) in uncommitted {
+                    self.apply(Write {
+                        key,
+                        value,
+                        version: version.clone(),
+                    });
+                }
+                txn
+            }
+        }
+    }
+
+    fn apply(&mut self, write: Write) {
+        self.registers
+            .insert(write.key, (write.version.clone(), write.value));
+        self.unreplicated.push(write);
+    }
+
+    /// Applies writes replicated from a peer, keeping the newest version of
+    /// each register. Writes that win are logged to be passed on.
+    pub fn merge(&mut self, writes: Vec<Write>) {
+        for write in writes {
+            self.clock = self.clock.max(write.version.0);
+            let newer = self
+                .registers
+                .get(&write.key)
+                .is_none_or(|(version, _)| write.version > *version);
+            if newer {
+                self.registers
+                    .insert(write.key, (write.version.clone(), write.value));
+                self.unreplicated.push(write);
+            }
+        }
+    }
+
+    /// Hands over the writes applied here since the last call, for gossip.
+    pub fn take_unreplicated(&mut self) -> Vec<Write> {
+        std::mem::take(&mut self.unreplicated)
+    }
+}
+
+/// The txn-rw-register workload. Writes reach other nodes through gossip,
+/// and are resent to each peer until it acknowledges them.
+pub struct TxnNode {
+    store: Store,
+    topology: Topology,
+    /// Every write applied here, to be passed on to our peers.
+    log: ReplicatedLog<Write>,
+    config: GossipConfig,
+}
+
+impl TxnNode {
+    pub fn new(isolation: Isolation, config: GossipConfig) -> Self {
+        TxnNode {
+            store: Store::new(isolation),
+            topology: Topology::new(),
+            log: ReplicatedLog::default(),
+            config,
+        }
+    }
+
+    /// Who we gossip with: our neighbours, or the whole cluster, in a fixed
+    /// order so that a seeded rng picks the same ones.
+    fn gossip_peers(&self, ctx: &Context) -> Vec<String> {
+        let mut peers: Vec<String> = match self.config.peers {
+            PeerSelection::Topology => self
+                .topology
+                .get(ctx.node_id())
+                .map(|peers| peers.iter().cloned().collect())
+                .unwrap_or_default(),
+            PeerSelection::Random => ctx.peers().cloned().collect(),
+        };
+        peers.sort();
+        peers
+    }
+
+    /// Moves whatever the store applied into the replicated log.
+    fn log_writes(&mut self) {
+        for write in self.store.take_unreplicated() {
+            self.log.push(write);
+        }
+    }
+}
+
+impl Handler for TxnNode {
+    type Payload = Payload;
+
+    /// Maelstrom sends no topology for this workload, so the one it suggests
+    /// is taken to be the whole cluster.
+    fn init(&mut self, ctx: &Context) {
+        self.store.set_node_id(ctx.node_id().to_string());
+        let everyone: Topology = ctx
+            .node_ids()
+            .iter()
+            .map(|node| {
+                let others = ctx.node_ids().iter().filter(|other| *other != node);
+                (node.clone(), others.cloned().collect())
+            })
+            .collect();
+        self.topology = self.config.topology.build(ctx.node_ids(), &everyone);
+    }
+
+    fn on_message(&mut self, ctx: &Context, msg: Message<Payload>) {
+        match &msg.body.message {
+            Payload::Txn { txn } => {
+                let txn = self.store.execute(txn.to_owned());
+                self.log_writes();
+                ctx.reply(&msg, Payload::TxnOk { txn });
+            }
+            Payload::TxnGossip { from, writes, ack } => {
+                self.log.acknowledge(&msg.src, *ack);
+                self.log.receive(&msg.src, *from, writes.len());
+                self.store.merge(writes.to_owned());
+                self.log_writes();
+            }
+            _ => ctx.not_supported(&msg),
+        }
+    }
+
+    /// Sends up to `fan_out` peers the writes they haven't acknowledged yet.
+    fn on_tick(&mut self, ctx: &Context) {
+        let behind = self
+            .gossip_peers(ctx)
+            .into_iter()
+            .filter(|peer| self.log.has_news_for(peer));
+        let peers = match self.config.fan_out {
+            Some(fan_out) => behind.choose_multiple(&mut *ctx.rng(), fan_out),
+            None => behind.collect(),
+        };
+        for peer in peers {
+            let (from, writes, ack) =
+                self.log
+                    .delta(&peer, self.config.max_payload, self.config.redundancy);
+            let writes = writes.to_vec();
+            self.log.sent(&peer, from + writes.len());
+            ctx.send(&peer, Payload::TxnGossip { from, writes, ack });
+        }
+    }
+
+    fn tick_interval(&self) -> Option<Duration> {
+        Some(self.config.period)
+    }
+
+    fn tick_jitter(&self) -> Duration {
+        self.config.jitter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Network, Simulation};
+
+    fn txn() -> Vec<MicroOp> {
+        vec![
+            MicroOp(Op::Write, 1, Some(1)),
+            MicroOp(Op::Write, 1, Some(2)),
+            MicroOp(Op::Read, 1, None),
+            MicroOp(Op::Read, 2, None),
+        ]
+    }
+
+    #[test]
+    fn test_isolation_levels() {
+        let mut uncommitted = Store::new(Isolation::ReadUncommitted);
+        let mut committed = Store::new(Isolation::ReadCommitted);
+        for store in [&mut uncommitted, &mut committed] {
+            assert_eq!(
+                store.execute(txn()),
+                vec![
+                    MicroOp(Op::Write, 1, Some(1)),
+                    MicroOp(Op::Write, 1, Some(2)),
+                    MicroOp(Op::Read, 1, Some(2)),
+                    MicroOp(Op::Read, 2, None),
+                ]
+            );
+        }
+
+        let values = |writes: Vec<Write>| writes.iter().map(|w| w.value).collect::<Vec<_>>();
+        assert_eq!(values(uncommitted.take_unreplicated()), vec![1, 2]);
+        assert_eq!(values(committed.take_unreplicated()), vec![2]);
+        assert!(committed.take_unreplicated().is_empty());
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_version() {
+        let mut store = Store::new(Isolation::ReadCommitted);
+        store.set_node_id("n1".to_string());
+        store.execute(vec![MicroOp(Op::Write, 1, Some(1))]);
+
+        store.merge(vec![Write {
+            key: 1,
+            value: 5,
+            version: Version(1, "n0".to_string()),
+        }]);
+        assert_eq!(store.read(1), Some(1));
+
+        store.merge(vec![Write {
+            key: 1,
+            value: 7,
+            version: Version(4, "n2".to_string()),
+        }]);
+        assert_eq!(store.read(1), Some(7));
+
+        // Later local transactions are ordered after everything we've seen
+        store.execute(vec![MicroOp(Op::Write, 1, Some(9))]);
+        assert_eq!(store.take_unreplicated().last().unwrap().version.0, 5);
+        assert_eq!(store.read(1), Some(9));
+    }
+
+    #[test]
+    fn test_writes_are_retired_once_every_peer_acknowledges() {
+        let network = Network {
+            drop_rate: 0.2,
+            ..Default::default()
+        };
+        let config = GossipConfig {
+            fan_out: Some(1),
+            max_payload: Some(2),
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(5, network, 3, || {
+            TxnNode::new(Isolation::ReadCommitted, config.clone())
+        });
+        let timeout = Duration::from_secs(1);
+        sim.partition(&[&["n0"], &["n1", "n2"]]);
+        for (node, key) in [("n0", 1), ("n1", 2), ("n2", 3), ("n0", 4), ("n1", 5)] {
+            let txn = vec![MicroOp(Op::Write, key, Some(key * 10))];
+            let reply: Option<Payload> = sim.call(node, Payload::Txn { txn }, timeout);
+            assert!(matches!(reply, Some(Payload::TxnOk { .. })));
+        }
+        sim.run_for(Duration::from_secs(2));
+        assert_eq!(sim.node("n0").store.read(2), None);
+        // n0 keeps its writes for the peers it can't reach
+        assert!(sim.node("n0").log.has_news_for("n1"));
+
+        sim.heal();
+        sim.run_for(Duration::from_secs(5));
+        let reads = (1..=5).map(|key| MicroOp(Op::Read, key, None)).collect();
+        let observed = (1..=5)
+            .map(|key| MicroOp(Op::Read, key, Some(key * 10)))
+            .collect();
+        sim.assert_converged(
+            Payload::Txn { txn: reads },
+            Payload::TxnOk { txn: observed },
+        );
+
+        // Nothing is resent once the last acks make it through
+        sim.run_for(Duration::from_secs(5));
+        for node in ["n0", "n1", "n2"] {
+            for peer in ["n0", "n1", "n2"].into_iter().filter(|peer| *peer != node) {
+                assert!(
+                    !sim.node(node).log.has_news_for(peer),
+                    "{} still has writes or an ack for {}",
+                    node,
+                    peer
+                );
+            }
+        }
+    }
+}
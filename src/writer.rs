@@ -0,0 +1,197 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde_json::Value;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::watch;
+use tracing::error;
+
+use crate::error::Error;
+use crate::transport::MessageSink;
+use crate::Message;
+
+/// How many messages may wait for the writer before senders have to.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Most messages written out between two flushes.
+const MAX_BATCH: usize = 256;
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// Messages accepted by `send`.
+    queued: AtomicU64,
+    messages: AtomicU64,
+    batches: AtomicU64,
+    largest_batch: AtomicUsize,
+    blocked_sends: AtomicU64,
+}
+
+/// A snapshot of how a `BatchWriter` is keeping up.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriterStats {
+    /// Messages queued but not yet written.
+    pub queue_depth: usize,
+    pub messages: u64,
+    /// Writes (and flushes) so far. Each one covers at least one message.
+    pub batches: u64,
+    pub largest_batch: usize,
+    /// Sends that had to wait for room in the queue.
+    pub blocked_sends: u64,
+}
+
+/// Writes messages as JSON lines from a task of its own, so that serializing
+/// and flushing never hold up the event loop. Whatever queued up while the
+/// last batch was being written goes out in one write with a single flush.
+/// Sends wait once `capacity` messages are queued.
+#[derive(Clone)]
+pub struct BatchWriter {
+    tx: mpsc::Sender<Message<Value>>,
+    counters: Arc<Counters>,
+    /// How many messages the writer task has written out so far.
+    written: watch::Receiver<u64>,
+}
+
+impl BatchWriter {
+    pub fn spawn<W>(out: W, capacity: usize) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(capacity);
+        let counters = Arc::new(Counters::default());
+        let (written_tx, written) = watch::channel(0);
+        tokio::spawn(write_batches(out, rx, counters.clone(), written_tx));
+        BatchWriter {
+            tx,
+            counters,
+            written,
+        }
+    }
+
+    pub fn stats(&self) -> WriterStats {
+        WriterStats {
+            queue_depth: self.tx.max_capacity() - self.tx.capacity(),
+            messages: self.counters.messages.load(Ordering::Relaxed),
+            batches: self.counters.batches.load(Ordering::Relaxed),
+            largest_batch: self.counters.largest_batch.load(Ordering::Relaxed),
+            blocked_sends: self.counters.blocked_sends.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl MessageSink for BatchWriter {
+    async fn send(&mut self, msg: Message<Value>) -> Result<(), Error> {
+        let sent = match self.tx.try_send(msg) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(msg)) => {
+                self.counters.blocked_sends.fetch_add(1, Ordering::Relaxed);
+                self.tx.send(msg).await.map_err(|_| ())
+            }
+            Err(TrySendError::Closed(_)) => Err(()),
+        };
+        sent.map_err(|_| stopped())?;
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Error> {
+        let queued = self.counters.queued.load(Ordering::Relaxed);
+        self.written
+            .wait_for(|written| *written >= queued)
+            .await
+            .map(|_| ())
+            .map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> Error {
+    std::io::Error::new(std::io::ErrorKind::BrokenPipe, "Writer task has stopped").into()
+}
+
+async fn write_batches<W: AsyncWrite + Unpin>(
+    mut out: W,
+    mut rx: mpsc::Receiver<Message<Value>>,
+    counters: Arc<Counters>,
+    written: watch::Sender<u64>,
+) {
+    let mut buffer = Vec::new();
+    while let Some(msg) = rx.recv().await {
+        let mut batch = vec![msg];
+        while batch.len() < MAX_BATCH {
+            match rx.try_recv() {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+
+        buffer.clear();
+        for msg in &batch {
+            serde_json::to_writer(&mut buffer, msg)
+                .expect("Failed to convert message (to be sent) to JSON.");
+            buffer.push(b'\n');
+        }
+        if let Err(err) = out.write_all(&buffer).await {
+            error!(%err, messages = batch.len(), "Failed to write");
+            return;
+        }
+        if let Err(err) = out.flush().await {
+            error!(%err, messages = batch.len(), "Failed to flush");
+            return;
+        }
+
+        counters
+            .messages
+            .fetch_add(batch.len() as u64, Ordering::Relaxed);
+        counters.batches.fetch_add(1, Ordering::Relaxed);
+        counters
+            .largest_batch
+            .fetch_max(batch.len(), Ordering::Relaxed);
+        written.send_modify(|written| *written += batch.len() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::*;
+    use crate::MessageBody;
+
+    #[tokio::test]
+    async fn test_queued_messages_share_a_flush() {
+        let (out, read) = tokio::io::duplex(64 * 1024);
+        let mut writer = BatchWriter::spawn(out, 16);
+
+        // The writer task can't run until we yield, so all of these queue up
+        for i in 0..10 {
+            let msg = Message {
+                src: "n1".to_string(),
+                dest: "c1".to_string(),
+                body: MessageBody {
+                    msg_id: Some(i),
+                    in_reply_to: None,
+                    message: json!({"type": "echo_ok"}),
+                },
+            };
+            writer.send(msg).await.expect("Writer stopped");
+        }
+        assert_eq!(writer.stats().queue_depth, 10);
+
+        let mut lines = BufReader::new(read).lines();
+        for i in 0..10 {
+            let line = lines
+                .next_line()
+                .await
+                .unwrap()
+                .expect("Message went missing");
+            let msg: Message<Value> = serde_json::from_str(&line).unwrap();
+            assert_eq!(msg.body.msg_id, Some(i));
+        }
+        let stats = writer.stats();
+        assert_eq!(stats.queue_depth, 0);
+        assert_eq!(stats.messages, 10);
+        assert_eq!(stats.batches, 1);
+        assert_eq!(stats.blocked_sends, 0);
+    }
+}